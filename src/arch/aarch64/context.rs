@@ -1,15 +1,21 @@
+/// Registers saved by `switch` at the offsets used in `switch.S`, which stores them in
+/// pairs downwards from offset 112. The field order must not be changed without changing
+/// it.
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct ContextData {
-    // callee saved registers
-    pub s: [usize; 11],
+    // callee saved registers x19-x29
+    pub s: [usize; 11], // 0..88
     // pc / sp
-    pub lr: usize,
-    pub sp: usize,
+    pub lr: usize, // 88, x30
+    pub sp: usize, // 96
     // pg base register
-    pub ttbr0: usize,
+    pub ttbr0: usize, // 104
 }
 
+// `switch.S` starts storing at offset 112
+const _: () = assert!(core::mem::size_of::<ContextData>() == 112);
+
 impl ContextData {
     pub fn new(lr: usize, sp: usize, ttbr0: usize) -> Self {
         Self {
@@ -20,3 +26,18 @@ impl ContextData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ContextData;
+    use memoffset::offset_of;
+
+    // the offsets `switch.S` saves the registers at
+    #[test]
+    fn field_offsets() {
+        assert_eq!(offset_of!(ContextData, s), 0);
+        assert_eq!(offset_of!(ContextData, lr), 88);
+        assert_eq!(offset_of!(ContextData, sp), 96);
+        assert_eq!(offset_of!(ContextData, ttbr0), 104);
+    }
+}
@@ -7,48 +7,82 @@ mod context;
 pub use context::*;
 
 global_asm!(include_str!("switch.S"));
-global_asm!(include_str!("executor_entry.S"));
 
 extern "C" {
-    pub fn switch(old: *const ContextData, new: *const ContextData);
-    pub fn executor_entry();
+    fn switch(old: *const ContextData, new: *const ContextData);
 }
 
-pub(crate) fn cpu_id() -> u8 {
-    (MPIDR_EL1.get() & 0xf) as u8
+/// executor 的入口函数, 获取栈上的 executor 实例地址后跳转到 runtime.rs::run_executor.
+#[naked]
+pub unsafe extern "C" fn executor_entry() {
+    core::arch::asm!(
+        "ldr x0, [sp]",
+        // terminate the frame pointer chain, so that backtraces stop at the executor entry
+        "mov x29, xzr",
+        "mov x30, xzr",
+        "b run_executor",
+        options(noreturn)
+    );
 }
 
 pub(crate) fn pg_base_addr() -> usize {
     TTBR0_EL1.get() as usize
 }
 
-pub(crate) fn pg_base_register() -> usize {
-    TTBR0_EL1.get() as usize
-}
+pub struct ArchImpl;
 
-pub(crate) fn wait_for_interrupt() {
-    let enable = intr_get();
-    if !enable {
-        intr_on();
+impl super::Arch for ArchImpl {
+    // Aff0, `check_cpu_id` makes sure that the other affinity levels are 0
+    fn cpu_id() -> u8 {
+        (MPIDR_EL1.get() & 0xff) as u8
     }
-    cortex_a::asm::wfi();
-    if !enable {
-        intr_off();
+
+    fn check_cpu_id() {
+        let mpidr = MPIDR_EL1.get();
+        // only Aff0 is used as cpu id, cores in different clusters would get the same id
+        assert!(
+            mpidr & 0xff_00ff_ff00 == 0,
+            "multi-cluster system is not supported, MPIDR_EL1 = 0x{:x}",
+            mpidr
+        );
     }
-}
 
-pub(crate) fn intr_on() {
-    unsafe {
-        core::arch::asm!("msr daifclr, #2");
+    fn intr_on() {
+        unsafe {
+            core::arch::asm!("msr daifclr, #2");
+        }
     }
-}
 
-pub(crate) fn intr_off() {
-    unsafe {
-        core::arch::asm!("msr daifset, #2");
+    fn intr_off() {
+        unsafe {
+            core::arch::asm!("msr daifset, #2");
+        }
+    }
+
+    fn intr_get() -> bool {
+        !DAIF.is_set(DAIF::I)
     }
-}
 
-pub(crate) fn intr_get() -> bool {
-    !DAIF.is_set(DAIF::I)
+    fn wait_for_interrupt() {
+        let enable = Self::intr_get();
+        if !enable {
+            Self::intr_on();
+        }
+        cortex_a::asm::wfi();
+        if !enable {
+            Self::intr_off();
+        }
+    }
+
+    fn pg_base_register() -> usize {
+        TTBR0_EL1.get() as usize
+    }
+
+    fn read_timer() -> u64 {
+        CNTPCT_EL0.get()
+    }
+
+    unsafe fn switch(old: *const ContextData, new: *const ContextData) {
+        switch(old, new)
+    }
 }
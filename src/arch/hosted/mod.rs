@@ -0,0 +1,214 @@
+//! Hosted backend, which runs the scheduler as a normal process on a x86_64 host, so that
+//! it can be tested without QEMU. Each thread plays the role of a cpu.
+
+use core::arch::{asm, global_asm};
+use core::cell::Cell;
+
+#[path = "../x86_64/context.rs"]
+mod context;
+
+pub use context::*;
+
+global_asm!(include_str!("switch.S"));
+
+extern "C" {
+    fn switch(old: *const ContextData, new: *const ContextData);
+}
+
+/// executor 的入口函数, 获取栈上的 executor 实例地址后跳转到 runtime.rs::run_executor.
+#[naked]
+pub unsafe extern "C" fn executor_entry() {
+    asm!(
+        "pop rdi",
+        "push 0", // make rsp 16-bytes aligned, and a null return address
+        // terminate the frame pointer chain, so that backtraces stop at the executor entry
+        "xor ebp, ebp",
+        "jmp run_executor",
+        options(noreturn)
+    );
+}
+
+std::thread_local! {
+    static CPU_ID: Cell<u8> = Cell::new(0);
+    static INTR_ENABLE: Cell<bool> = Cell::new(false);
+}
+
+/// Bind current thread to the (simulated) cpu `cpu_id`.
+pub fn bind_cpu(cpu_id: u8) {
+    CPU_ID.with(|id| id.set(cpu_id));
+}
+
+pub struct ArchImpl;
+
+impl super::Arch for ArchImpl {
+    fn cpu_id() -> u8 {
+        CPU_ID.with(|id| id.get())
+    }
+
+    fn intr_on() {
+        INTR_ENABLE.with(|enable| enable.set(true));
+    }
+
+    fn intr_off() {
+        INTR_ENABLE.with(|enable| enable.set(false));
+    }
+
+    fn intr_get() -> bool {
+        INTR_ENABLE.with(|enable| enable.get())
+    }
+
+    fn wait_for_interrupt() {
+        std::thread::yield_now();
+    }
+
+    fn pg_base_register() -> usize {
+        0
+    }
+
+    fn read_timer() -> u64 {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    unsafe fn switch(old: *const ContextData, new: *const ContextData) {
+        switch(old, new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bind_cpu;
+    use crate::executor::with_current_executor;
+    use crate::runtime::{
+        get_current_executor_id, resume_executor, spawn_task, suspend_current_executor,
+        MAX_PARKED_EXECUTORS,
+    };
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use core::task::{Context, Poll, Waker};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    // Each test runs the scheduler of its own cpu in a thread, which never returns and is
+    // killed when the test process exits.
+    fn start_cpu(cpu_id: u8) {
+        std::thread::spawn(move || {
+            bind_cpu(cpu_id);
+            crate::run_until_idle();
+        });
+    }
+
+    fn wait_until(cond: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !cond() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            std::thread::yield_now();
+        }
+    }
+
+    // pending on the first poll, after saving the waker, and ready on the next one
+    struct WaitForWake {
+        waker: Arc<Mutex<Option<Waker>>>,
+        polls: Arc<AtomicUsize>,
+    }
+
+    impl Future for WaitForWake {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.polls.fetch_add(1, Ordering::SeqCst) == 0 {
+                *self.waker.lock().unwrap() = Some(cx.waker().clone());
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        }
+    }
+
+    #[test]
+    fn spawn_run_complete() {
+        let cpu_id = 1;
+        let done = Arc::new(AtomicBool::new(false));
+        let done2 = done.clone();
+        spawn_task(
+            async move { done2.store(true, Ordering::SeqCst) },
+            None,
+            Some(cpu_id as usize),
+        )
+        .unwrap();
+        start_cpu(cpu_id);
+        wait_until(|| done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn wake_from_another_thread() {
+        let cpu_id = 2;
+        let waker = Arc::new(Mutex::new(None));
+        let polls = Arc::new(AtomicUsize::new(0));
+        let future = WaitForWake {
+            waker: waker.clone(),
+            polls: polls.clone(),
+        };
+        spawn_task(future, None, Some(cpu_id as usize)).unwrap();
+        start_cpu(cpu_id);
+        wait_until(|| waker.lock().unwrap().is_some());
+        // never polled again until woken
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        waker.lock().unwrap().take().unwrap().wake();
+        wait_until(|| polls.load(Ordering::SeqCst) == 2);
+    }
+
+    #[test]
+    fn current_executor_of_task() {
+        let cpu_id = 3;
+        let ids = Arc::new(Mutex::new(None));
+        let ids2 = ids.clone();
+        let task = async move {
+            let executor_id = with_current_executor(|executor| executor.id());
+            *ids2.lock().unwrap() = Some((executor_id, get_current_executor_id().0));
+        };
+        spawn_task(task, None, Some(cpu_id as usize)).unwrap();
+        start_cpu(cpu_id);
+        wait_until(|| ids.lock().unwrap().is_some());
+        let (executor_id, expected) = ids.lock().unwrap().unwrap();
+        assert_eq!(executor_id, Some(expected));
+        // not called from a task
+        assert!(with_current_executor(|executor| executor.id()).is_none());
+    }
+
+    #[test]
+    fn suspend_more_executors_than_slots() {
+        let cpu_id = 4;
+        // more than the slots, and one more than can be parked: all but one are suspended
+        let tasks = MAX_PARKED_EXECUTORS + 1;
+        let suspended = Arc::new(Mutex::new(Vec::new()));
+        // whether each task was suspended, checked here since a panic can not unwind
+        // through the stack of an executor
+        let results = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..tasks {
+            let suspended = suspended.clone();
+            let results = results.clone();
+            let task = async move {
+                let (executor_id, _) = get_current_executor_id();
+                suspended.lock().unwrap().push(executor_id);
+                let result = suspend_current_executor();
+                results.lock().unwrap().push(result);
+            };
+            spawn_task(task, None, Some(cpu_id as usize)).unwrap();
+        }
+        start_cpu(cpu_id);
+        wait_until(|| suspended.lock().unwrap().len() == tasks);
+        let executor_ids = suspended.lock().unwrap().clone();
+        let resume = async move {
+            for executor_id in executor_ids {
+                resume_executor(executor_id);
+            }
+        };
+        spawn_task(resume, None, Some(cpu_id as usize)).unwrap();
+        wait_until(|| results.lock().unwrap().len() == tasks);
+        let results = results.lock().unwrap();
+        let suspended = results.iter().filter(|suspended| **suspended).count();
+        assert_eq!(suspended, MAX_PARKED_EXECUTORS);
+    }
+}
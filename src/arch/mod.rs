@@ -0,0 +1,91 @@
+//! Architecture dependent code. Every arch module provides an `ArchImpl` implementing
+//! [`Arch`], the free functions below dispatch to the one of current target.
+
+cfg_if::cfg_if! {
+  if #[cfg(all(feature = "std", target_arch = "x86_64"))] {
+      #[path = "hosted/mod.rs"]
+      mod imp;
+      pub use imp::bind_cpu;
+  } else if #[cfg(feature = "std")] {
+      compile_error!("the hosted target is only supported on x86_64");
+  } else if #[cfg(target_arch = "x86_64")] {
+      #[path = "x86_64/mod.rs"]
+      mod imp;
+  } else if #[cfg(target_arch = "riscv64")] {
+      #[path = "riscv64/mod.rs"]
+      mod imp;
+  } else if #[cfg(target_arch = "aarch64")] {
+      #[path = "aarch64/mod.rs"]
+      mod imp;
+  }
+}
+
+pub use imp::{executor_entry, ContextData};
+
+pub type CurrentArch = imp::ArchImpl;
+
+pub trait Arch {
+    /// id of current cpu.
+    fn cpu_id() -> u8;
+
+    /// Check that `cpu_id` can identify current cpu. It is called once on each cpu by
+    /// `register_executor_runtime`, so that `cpu_id` itself stays cheap.
+    fn check_cpu_id() {}
+
+    /// enable interrupts on current cpu.
+    fn intr_on();
+
+    /// disable interrupts on current cpu.
+    fn intr_off();
+
+    /// whether interrupts are enabled on current cpu.
+    fn intr_get() -> bool;
+
+    /// wait until an interrupt arrives, the interrupt enable state is not changed.
+    fn wait_for_interrupt();
+
+    /// the value of page table base register.
+    fn pg_base_register() -> usize;
+
+    /// a monotonic counter, used for profiling.
+    fn read_timer() -> u64;
+
+    /// save callee saved registers to `old` and load them from `new`.
+    unsafe fn switch(old: *const ContextData, new: *const ContextData);
+}
+
+pub(crate) fn cpu_id() -> u8 {
+    CurrentArch::cpu_id()
+}
+
+pub(crate) fn check_cpu_id() {
+    CurrentArch::check_cpu_id()
+}
+
+pub(crate) fn intr_on() {
+    CurrentArch::intr_on()
+}
+
+pub(crate) fn intr_off() {
+    CurrentArch::intr_off()
+}
+
+pub(crate) fn intr_get() -> bool {
+    CurrentArch::intr_get()
+}
+
+pub(crate) fn wait_for_interrupt() {
+    CurrentArch::wait_for_interrupt()
+}
+
+pub(crate) fn pg_base_register() -> usize {
+    CurrentArch::pg_base_register()
+}
+
+pub(crate) fn read_timer() -> u64 {
+    CurrentArch::read_timer()
+}
+
+pub(crate) unsafe fn switch(old: *const ContextData, new: *const ContextData) {
+    CurrentArch::switch(old, new)
+}
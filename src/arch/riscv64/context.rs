@@ -1,15 +1,20 @@
+/// Registers saved by `switch` at the offsets used in `switch.S`, the field order must
+/// not be changed without changing it.
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct ContextData {
     // pc / sp
-    pub ra: usize,
-    pub sp: usize,
-    // callee saved registers
-    pub s: [usize; 12],
+    pub ra: usize, // 0
+    pub sp: usize, // 8
+    // callee saved registers s0-s11
+    pub s: [usize; 12], // 16..112
     // pg base register
-    pub satp: usize,
+    pub satp: usize, // 112
 }
 
+// `switch.S` stores satp at offset 112
+const _: () = assert!(core::mem::size_of::<ContextData>() == 120);
+
 impl ContextData {
     pub fn new(ra: usize, sp: usize, satp: usize) -> Self {
         Self {
@@ -20,3 +25,18 @@ impl ContextData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ContextData;
+    use memoffset::offset_of;
+
+    // the offsets `switch.S` saves the registers at
+    #[test]
+    fn field_offsets() {
+        assert_eq!(offset_of!(ContextData, ra), 0);
+        assert_eq!(offset_of!(ContextData, sp), 8);
+        assert_eq!(offset_of!(ContextData, s), 16);
+        assert_eq!(offset_of!(ContextData, satp), 112);
+    }
+}
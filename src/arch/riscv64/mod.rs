@@ -1,61 +1,94 @@
 use core::arch::{asm, global_asm};
+use riscv::register::sstatus;
 
 mod context;
 
 pub use context::*;
 
 global_asm!(include_str!("switch.S"));
-global_asm!(include_str!("executor_entry.S"));
 
 extern "C" {
-    pub fn switch(old: *const ContextData, new: *const ContextData);
-    pub fn executor_entry();
+    fn switch(old: *const ContextData, new: *const ContextData);
 }
 
-pub(crate) fn cpu_id() -> u8 {
-    let mut cpu_id;
-    unsafe {
-        asm!("mv {0}, tp", out(reg) cpu_id, options(nomem, nostack));
-    }
-    cpu_id
+/// executor 的入口函数, 获取栈上的 executor 实例地址后跳转到 runtime.rs::run_executor.
+#[naked]
+pub unsafe extern "C" fn executor_entry() {
+    asm!(
+        "ld a0, 0(sp)",
+        "addi sp, sp, 8",
+        // terminate the frame pointer chain, so that backtraces stop at the executor entry
+        "mv fp, zero",
+        "mv ra, zero",
+        "j run_executor",
+        options(noreturn)
+    );
 }
 
 pub(crate) fn pg_base_addr() -> usize {
     riscv::register::satp::read().ppn() << 12
 }
 
-pub(crate) fn pg_base_register() -> usize {
-    riscv::register::satp::read().bits()
-}
+// pub(crate) fn is_handling_intr() -> bool {
+//     sstatus::read().spp() == sstatus::SPP::Supervisor
+// }
 
-use riscv::{asm, register::sstatus};
+pub struct ArchImpl;
 
-// FIXME: somethings may go wrong if an interrupt happened between sstatus::set_sie() and asm::wfi()
-pub(crate) fn wait_for_interrupt() {
-    let enable = sstatus::read().sie();
-    if !enable {
-        unsafe { sstatus::set_sie() };
+impl super::Arch for ArchImpl {
+    // The hart id is kept in `tp`, which the kernel must set in its entry code on each
+    // hart (e.g. from the `a0` passed by SBI) before running the scheduler. An uninitialized
+    // `tp` cannot be told from a valid one, so only check it is in range.
+    fn cpu_id() -> u8 {
+        let cpu_id: usize;
+        unsafe {
+            asm!("mv {0}, tp", out(reg) cpu_id, options(nomem, nostack));
+        }
+        debug_assert!(
+            cpu_id < crate::percpu::MAX_CPU_NUM,
+            "invalid hart id {} in tp, is tp initialized?",
+            cpu_id
+        );
+        cpu_id as u8
     }
-    unsafe {
-        asm::wfi();
+
+    fn intr_on() {
+        unsafe { sstatus::set_sie() };
     }
-    if !enable {
+
+    fn intr_off() {
         unsafe { sstatus::clear_sie() };
     }
-}
 
-pub(crate) fn intr_on() {
-    unsafe { sstatus::set_sie() };
-}
+    fn intr_get() -> bool {
+        sstatus::read().sie()
+    }
 
-pub(crate) fn intr_off() {
-    unsafe { sstatus::clear_sie() };
-}
+    // The executor runs with interrupts disabled, so they must be enabled before wfi,
+    // otherwise the hart may never wake up. The previous state is restored afterwards.
+    // FIXME: somethings may go wrong if an interrupt happened between sstatus::set_sie() and asm::wfi()
+    fn wait_for_interrupt() {
+        let enable = Self::intr_get();
+        if !enable {
+            Self::intr_on();
+        }
+        unsafe {
+            riscv::asm::wfi();
+        }
+        if !enable {
+            Self::intr_off();
+        }
+    }
 
-pub(crate) fn intr_get() -> bool {
-    sstatus::read().sie()
-}
+    fn pg_base_register() -> usize {
+        riscv::register::satp::read().bits()
+    }
 
-// pub(crate) fn is_handling_intr() -> bool {
-//     sstatus::read().spp() == sstatus::SPP::Supervisor
-// }
+    fn read_timer() -> u64 {
+        riscv::register::time::read64()
+    }
+
+    unsafe fn switch(old: *const ContextData, new: *const ContextData) {
+        switch(old, new)
+    }
+}
@@ -1,19 +1,25 @@
+/// Registers saved by `switch`, pushed on the stack of the switched-out context, so that
+/// the saved stack pointer points to this struct. The field order is the reverse of the
+/// push order in `switch.S` and must not be changed without changing it.
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C)]
 pub struct ContextData {
-    // pg base register
-    pub cr3: usize,
+    // pg base register, a dummy slot on the hosted target
+    pub cr3: usize, // 0
     // callee saved registers
-    pub r15: usize,
-    pub r14: usize,
-    pub r13: usize,
-    pub r12: usize,
-    pub rbp: usize,
-    pub rbx: usize,
-    // pc
-    pub rip: usize,
+    pub r15: usize, // 8
+    pub r14: usize, // 16
+    pub r13: usize, // 24
+    pub r12: usize, // 32
+    pub rbp: usize, // 40
+    pub rbx: usize, // 48
+    // pc, pushed by `call switch`
+    pub rip: usize, // 56
 }
 
+// `switch.S` pushes 7 registers after the return address
+const _: () = assert!(core::mem::size_of::<ContextData>() == 64);
+
 impl ContextData {
     pub fn new(rip: usize, _sp: usize, cr3: usize) -> Self {
         Self {
@@ -23,3 +29,22 @@ impl ContextData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ContextData;
+    use memoffset::offset_of;
+
+    // the offsets `switch.S` saves the registers at
+    #[test]
+    fn field_offsets() {
+        assert_eq!(offset_of!(ContextData, cr3), 0);
+        assert_eq!(offset_of!(ContextData, r15), 8);
+        assert_eq!(offset_of!(ContextData, r14), 16);
+        assert_eq!(offset_of!(ContextData, r13), 24);
+        assert_eq!(offset_of!(ContextData, r12), 32);
+        assert_eq!(offset_of!(ContextData, rbp), 40);
+        assert_eq!(offset_of!(ContextData, rbx), 48);
+        assert_eq!(offset_of!(ContextData, rip), 56);
+    }
+}
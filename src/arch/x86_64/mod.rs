@@ -1,76 +1,123 @@
 use core::arch::{asm, global_asm};
+use x86_64::instructions::interrupts;
 
 mod context;
 
 pub use context::*;
 
 global_asm!(include_str!("switch.S"));
-global_asm!(include_str!("executor_entry.S"));
 
 extern "C" {
-    pub fn switch(old: *const ContextData, new: *const ContextData);
-    pub fn executor_entry();
+    fn switch(old: *const ContextData, new: *const ContextData);
+}
+
+/// executor 的入口函数, 获取栈上的 executor 实例地址后跳转到 runtime.rs::run_executor.
+#[naked]
+pub unsafe extern "C" fn executor_entry() {
+    asm!(
+        "pop rdi",
+        "push 0", // make rsp 16-bytes aligned, and a null return address
+        // terminate the frame pointer chain, so that backtraces stop at the executor entry
+        "xor ebp, ebp",
+        "jmp run_executor",
+        options(noreturn)
+    );
 }
 
-pub(crate) fn cpu_id() -> u8 {
-    /*
-    raw_cpuid::CpuId::new()
-        .get_feature_info()
-        .unwrap()
-        .initial_local_apic_id() as u8
-    */
+/// Physical address of the page table, i.e. cr3 without the PCID and flags in bits 0-11.
+/// Use `pg_base_register` for the value to restore, which keeps them.
+pub(crate) fn pg_base_addr() -> usize {
+    x86_64::registers::control::Cr3::read()
+        .0
+        .start_address()
+        .as_u64() as _
+}
+
+// The kernel must point IA32_GS_BASE to its per-cpu area, which holds the cpu id at
+// offset 28, before running the scheduler.
+fn read_cpu_id() -> u64 {
     let cpu_id: u64;
     unsafe {
         asm!("mov {}, gs:28", out(reg) cpu_id);
     }
-    cpu_id as u8
+    cpu_id
 }
 
-// pub(crate) fn pg_base_addr() -> usize {
-//     x86_64::registers::control::Cr3::read()
-//         .0
-//         .start_address()
-//         .as_u64() as _
-// }
+pub struct ArchImpl;
 
-pub(crate) fn pg_base_register() -> usize {
-    let mut cr3;
-    unsafe {
-        asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+impl super::Arch for ArchImpl {
+    fn cpu_id() -> u8 {
+        /*
+        raw_cpuid::CpuId::new()
+            .get_feature_info()
+            .unwrap()
+            .initial_local_apic_id() as u8
+        */
+        // `check_cpu_id` makes sure that the value fits
+        read_cpu_id() as u8
     }
-    cr3
-}
 
-use x86_64::instructions::interrupts;
+    fn check_cpu_id() {
+        assert!(
+            x86_64::registers::model_specific::GsBase::read().as_u64() != 0,
+            "GS base is not set up"
+        );
+        // the raw value is checked, truncating it first could turn garbage into a valid id
+        let cpu_id = read_cpu_id();
+        assert!(
+            cpu_id < crate::percpu::MAX_CPU_NUM as u64,
+            "invalid cpu id {} at gs:28",
+            cpu_id
+        );
+    }
 
-pub(crate) fn wait_for_interrupt() {
-    /*
-    let enable = interrupts::are_enabled();
-    interrupts::enable_and_hlt();
-    if !enable {
-        interrupts::disable();
+    fn intr_on() {
+        interrupts::enable();
     }
-    */
-    // Hack: on x86_64 we only wait for a while. If there were not any interrupts,
-    // we just continue the executor's event loop.
-    let enable = interrupts::are_enabled();
-    let read_timer = || unsafe { core::arch::x86_64::_rdtsc() };
-    let start = read_timer();
-    interrupts::enable();
-    //while read_timer() < start + 100 {}
-    if !enable {
+
+    fn intr_off() {
         interrupts::disable();
     }
-}
 
-pub(crate) fn intr_on() {
-    interrupts::enable();
-}
+    fn intr_get() -> bool {
+        interrupts::are_enabled()
+    }
 
-pub(crate) fn intr_off() {
-    interrupts::disable();
-}
+    fn wait_for_interrupt() {
+        let enable = interrupts::are_enabled();
+        if cfg!(feature = "hlt-in-idle") {
+            // `sti; hlt`, the cpu is halted until the next interrupt
+            interrupts::enable_and_hlt();
+        } else {
+            // Hack: on x86_64 we only wait for a while. If there were not any interrupts,
+            // we just continue the executor's event loop. This is for platforms which do
+            // not deliver interrupts to halted cpus.
+            let read_timer = || unsafe { core::arch::x86_64::_rdtsc() };
+            let start = read_timer();
+            interrupts::enable();
+            while read_timer() < start + 100 {
+                core::hint::spin_loop();
+            }
+        }
+        if !enable {
+            interrupts::disable();
+        }
+    }
 
-pub(crate) fn intr_get() -> bool {
-    interrupts::are_enabled()
+    // the whole cr3, including the PCID in bits 0-11, which `switch` restores as is
+    fn pg_base_register() -> usize {
+        let mut cr3;
+        unsafe {
+            asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        }
+        cr3
+    }
+
+    fn read_timer() -> u64 {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+
+    unsafe fn switch(old: *const ContextData, new: *const ContextData) {
+        switch(old, new)
+    }
 }
@@ -0,0 +1,39 @@
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    future::Future,
+    task::{Context, Poll},
+};
+
+use crate::waker_page::WakerPage;
+
+// must be the same type as the one used by `WakerPage`
+#[cfg(not(loom))]
+use core::sync::atomic::AtomicBool;
+#[cfg(loom)]
+use loom::sync::atomic::AtomicBool;
+
+/// Drive `future` to completion on current cpu and return its output, without the runtime.
+///
+/// The future is only polled again after it is woken, the cpu waits for interrupts in
+/// between. Useful to call async code before the runtime starts, e.g. in kernel `main`.
+pub fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let mut future = Box::pin(future);
+    // a private page holding a single future, so that it is woken like a spawned task. It
+    // belongs to no collection, its notification is not counted as ready.
+    let page = WakerPage::new(crate::arch::cpu_id(), None);
+    page.initialize(0);
+    let dropped = Arc::new(AtomicBool::new(false));
+    let waker_ref = Arc::new(page.make_waker(0, &dropped));
+    let waker = woke::waker_ref(&waker_ref);
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if page.take_notified() & 1 == 0 {
+            crate::arch::wait_for_interrupt();
+            continue;
+        }
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            waker_ref.drop_by_ref();
+            return output;
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Portable monotonic clock, used for profiling and timers.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Nanoseconds converted from clock cycles.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Nanoseconds(pub u64);
+
+pub trait ClockSource: Send + Sync {
+    /// current value of the monotonic counter.
+    fn now_cycles(&self) -> u64;
+
+    /// frequency of the counter in Hz, 0 if unknown.
+    fn frequency(&self) -> u64;
+
+    /// convert a number of cycles to nanoseconds. If the frequency is unknown, the cycles
+    /// are returned as is.
+    fn cycles_to_ns(&self, cycles: u64) -> Nanoseconds {
+        match self.frequency() {
+            0 => Nanoseconds(cycles),
+            freq => Nanoseconds((cycles as u128 * 1_000_000_000 / freq as u128) as u64),
+        }
+    }
+
+    /// convert nanoseconds to a number of cycles, the inverse of `cycles_to_ns`.
+    fn ns_to_cycles(&self, ns: Nanoseconds) -> u64 {
+        match self.frequency() {
+            0 => ns.0,
+            freq => (ns.0 as u128 * freq as u128 / 1_000_000_000) as u64,
+        }
+    }
+}
+
+/// The default clock source, which reads the arch timer (`rdtsc` on x86_64, `time` on
+/// riscv64, `CNTPCT_EL0` on aarch64).
+pub struct ArchClock {
+    frequency: u64,
+}
+
+impl ArchClock {
+    pub const fn new(frequency: u64) -> Self {
+        Self { frequency }
+    }
+}
+
+impl ClockSource for ArchClock {
+    fn now_cycles(&self) -> u64 {
+        crate::arch::read_timer()
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+}
+
+static ARCH_CLOCK: ArchClock = ArchClock::new(0);
+
+const UNSET: u8 = 0;
+const SETTING: u8 = 1;
+const SET: u8 = 2;
+
+/// The clock source, written once by `set_clock_source` and read without a lock: the timer
+/// interrupt reads it too, and would spin forever on a lock held by the task it interrupted.
+struct ClockSlot {
+    state: AtomicU8,
+    clock: UnsafeCell<&'static dyn ClockSource>,
+}
+
+// SAFETY: `clock` is only written once, by the caller which moved `state` to `SETTING`, and
+// only read after `state` is `SET`
+unsafe impl Sync for ClockSlot {}
+
+impl ClockSlot {
+    fn get(&self) -> &'static dyn ClockSource {
+        if self.state.load(Ordering::Acquire) == SET {
+            // SAFETY: the clock is not written anymore once `state` is `SET`
+            unsafe { *self.clock.get() }
+        } else {
+            &ARCH_CLOCK
+        }
+    }
+}
+
+static CLOCK_SOURCE: ClockSlot = ClockSlot {
+    state: AtomicU8::new(UNSET),
+    clock: UnsafeCell::new(&ARCH_CLOCK),
+};
+
+/// Replace the default `ArchClock`, should be called once during initialization.
+///
+/// Panics if the clock source is set already.
+pub fn set_clock_source(clock: &'static dyn ClockSource) {
+    CLOCK_SOURCE
+        .state
+        .compare_exchange(UNSET, SETTING, Ordering::Acquire, Ordering::Relaxed)
+        .expect("the clock source is set already");
+    // SAFETY: `state` is `SETTING`, so nobody else writes or reads the clock
+    unsafe { *CLOCK_SOURCE.clock.get() = clock };
+    CLOCK_SOURCE.state.store(SET, Ordering::Release);
+}
+
+pub fn now_cycles() -> u64 {
+    CLOCK_SOURCE.get().now_cycles()
+}
+
+pub fn cycles_to_ns(cycles: u64) -> Nanoseconds {
+    CLOCK_SOURCE.get().cycles_to_ns(cycles)
+}
+
+pub fn ns_to_cycles(ns: Nanoseconds) -> u64 {
+    CLOCK_SOURCE.get().ns_to_cycles(ns)
+}
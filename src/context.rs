@@ -1,10 +1,16 @@
 pub use crate::arch::ContextData;
 
+/// The saved stack pointer (x86_64) or the address of the `ContextData` (other archs) of
+/// a runtime or an executor. On x86_64 `switch` stores to it directly, so it is aligned
+/// at least like `ContextData`.
 #[derive(Debug, Default)]
+#[repr(C, align(8))]
 pub struct Context {
     context: usize,
 }
 
+const _: () = assert!(core::mem::align_of::<Context>() >= core::mem::align_of::<ContextData>());
+
 impl Context {
     pub fn set_context(&mut self, addr: usize) {
         self.context = addr;
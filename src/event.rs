@@ -0,0 +1,78 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use lock::Mutex;
+
+/// A set/reset event, usually set by an interrupt handler and waited by a task. Unlike
+/// `Notify`, the state persists: waiting on an event that is already set returns at once.
+///
+/// Only one task is woken, the last one that polled `wait`.
+pub struct Event {
+    set: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Set the event and wake the waiting task. Can be called from interrupt context.
+    pub fn set(&self) {
+        // only the caller which changes the state wakes the task
+        if self
+            .set
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            if let Some(waker) = self.waker.lock().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn reset(&self) {
+        self.set.store(false, Ordering::Release);
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Wait until the event is set.
+    pub fn wait(&self) -> EventWait<'_> {
+        EventWait { event: self }
+    }
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct EventWait<'a> {
+    event: &'a Event,
+}
+
+impl Future for EventWait<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.event.is_set() {
+            return Poll::Ready(());
+        }
+        *self.event.waker.lock() = Some(cx.waker().clone());
+        // the event may be set before the waker is registered
+        if self.event.is_set() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
@@ -9,54 +9,248 @@ use {
 };
 
 use crate::arch::executor_entry;
-use crate::task_collection::TaskCollection;
+use crate::percpu::{PerCpu, MAX_CPU_NUM};
+use crate::task_collection::{TaskCollection, TaskState};
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use lazy_static::*;
+use lock::Mutex;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 enum ExecutorState {
     STRONG,
     WEAK, // 执行完一次future后就需要被drop
     KILLED,
     UNUSED,
+    SUSPENDED, // 暂停运行, 直到被 resume 之前 runtime 都不会切换到该 executor
+}
+
+impl ExecutorState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => ExecutorState::STRONG,
+            1 => ExecutorState::WEAK,
+            2 => ExecutorState::KILLED,
+            3 => ExecutorState::UNUSED,
+            4 => ExecutorState::SUSPENDED,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Whether an executor is inside the poll of a future, see `Executor::is_running_future`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
+enum PollState {
+    IDLE,
+    POLLING,
 }
 
 pub struct Executor {
     id: usize,
     task_collection: Arc<TaskCollection>,
-    stack_base: usize,
-    pub context: ExecuterContext,
+    stack: NonNull<u8>,
+    stack_size: usize,
+    // the allocator of `stack`, used again to deallocate it
+    stack_allocator: Arc<dyn Allocator + Send + Sync>,
+    pub(crate) context: ExecuterContext,
     #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
     context_data: ContextData,
-    task_id: usize,
-    state: ExecutorState,
+    // id of the task being polled, atomic since `run` only borrows the executor shared: the
+    // runtime and the task itself reach the executor while it runs
+    task_id: AtomicUsize,
+    // a `PollState`, only changed by the executor itself
+    poll_state: AtomicU8,
+    // an `ExecutorState`, atomic so that the runtime changes it through the shared `Arc`
+    state: AtomicU8,
+    // the cpu whose runtime owns this executor
+    pinned_cpu: u8,
+    // number of tasks polled before giving weak executors a chance to run
+    polling_quantum: usize,
+}
+
+pub(crate) const STACK_SIZE: usize = 4096 * 32;
+const STACK_ALIGN: usize = 16;
+// yield to the weak executors after each poll, for latency
+const DEFAULT_POLLING_QUANTUM: usize = 1;
+
+fn stack_layout(stack_size: usize) -> Layout {
+    Layout::from_size_align(stack_size, STACK_ALIGN).expect("invalid executor stack size")
+}
+
+// 写在栈两端的 canary, 用于检测栈溢出或野指针造成的栈破坏
+const STACK_CANARY: usize = 0xCAFE_BABE_DEAD_BEEF;
+// 栈顶为 canary 保留的空间, 保持 16 字节对齐
+const STACK_CANARY_RESERVED: usize = 16;
+
+fn default_stack_corruption_handler(executor_id: usize, stack_base: usize) {
+    panic!(
+        "stack of executor {} (base 0x{:x}) is corrupted",
+        executor_id, stack_base
+    );
+}
+
+lazy_static! {
+    static ref STACK_CORRUPTION_HANDLER: Mutex<fn(usize, usize)> =
+        Mutex::new(default_stack_corruption_handler);
+    // id of the task being polled on each cpu, 0 if none
+    static ref CURRENT_TASK: PerCpu<AtomicUsize> =
+        PerCpu::new(MAX_CPU_NUM, |_| AtomicUsize::new(0));
+    // the executor switched to on each cpu, null when the runtime runs
+    static ref CURRENT_EXECUTOR: PerCpu<AtomicPtr<Executor>> =
+        PerCpu::new(MAX_CPU_NUM, |_| AtomicPtr::new(core::ptr::null_mut()));
+}
+
+pub(crate) fn set_current_task(task_id: usize) {
+    CURRENT_TASK.get().store(task_id, Ordering::Relaxed);
+}
+
+/// Record the executor the runtime switches to, it must be kept alive until
+/// `clear_current_executor` is called.
+pub(crate) fn set_current_executor(executor: &Executor) {
+    CURRENT_EXECUTOR
+        .get()
+        .store(executor as *const _ as *mut _, Ordering::Relaxed);
+}
+
+pub(crate) fn clear_current_executor() {
+    CURRENT_EXECUTOR
+        .get()
+        .store(core::ptr::null_mut(), Ordering::Relaxed);
+}
+
+/// Call `f` with the id of the task running on current cpu, without borrowing the runtime.
+/// Return `None` if it is not called from a task.
+pub fn with_current_task<R>(f: impl FnOnce(usize) -> R) -> Option<R> {
+    match CURRENT_TASK.get().load(Ordering::Relaxed) {
+        0 => None,
+        task_id => Some(f(task_id)),
+    }
+}
+
+/// Call `f` with the executor polling the task of current cpu, without borrowing the
+/// runtime. Return `None` if it is not called from a task.
+///
+/// The executor is only lent for the call: it may be killed and dropped once its task
+/// finishes, so it must not be kept.
+pub fn with_current_executor<R>(f: impl FnOnce(&Executor) -> R) -> Option<R> {
+    with_current_task(|_| ())?;
+    let executor = CURRENT_EXECUTOR.get().load(Ordering::Relaxed);
+    // SAFETY: set by the runtime before switching to the executor, which is held by
+    // `current_executor` until it is cleared. The task calling this runs on the executor,
+    // so it is not cleared before `f` returns. `run` only borrows the executor shared, so
+    // the reference does not alias a mutable one.
+    unsafe { executor.as_ref() }.map(f)
 }
 
-const STACK_SIZE: usize = 4096 * 32;
-const STACK_LAYOUT: Layout = Layout::new::<[u8; STACK_SIZE]>();
+// cycles of tasks an executor runs before it yields to the runtime, 0 means no budget
+static TIME_BUDGET: AtomicU64 = AtomicU64::new(0);
+
+/// Make executors yield to the runtime after running tasks for `cycles` cycles of the clock
+/// source, checked after each poll. It works like preemption without timer interrupts, but
+/// a single long poll is not interrupted. 0 disables the budget, which is the default.
+pub fn set_time_budget(cycles: u64) {
+    TIME_BUDGET.store(cycles, Ordering::Relaxed);
+}
+
+// the start of a budget, the clock is not read on the hot path while there is no budget
+fn budget_start_cycles() -> u64 {
+    if TIME_BUDGET.load(Ordering::Relaxed) != 0 {
+        crate::clock::now_cycles()
+    } else {
+        0
+    }
+}
+
+/// Override the handler called with `(executor_id, stack_base)` when the stack canary
+/// of an executor is found corrupted. The default handler panics.
+pub fn set_stack_corruption_handler(handler: fn(usize, usize)) {
+    *STACK_CORRUPTION_HANDLER.lock() = handler;
+}
 
 fn executor_alloc_id() -> usize {
-    use core::sync::atomic::{AtomicUsize, Ordering};
     static EXECUTOR_ID: AtomicUsize = AtomicUsize::new(1);
     EXECUTOR_ID.fetch_add(1, Ordering::SeqCst)
 }
 
 impl Executor {
     pub fn new(task_collection: Arc<TaskCollection>) -> Pin<Box<Self>> {
-        let stack: NonNull<u8> = Global
-            .allocate(STACK_LAYOUT)
+        Self::with_polling_quantum(task_collection, DEFAULT_POLLING_QUANTUM)
+    }
+
+    /// Create an executor whose stack of `stack_size` bytes is allocated by `allocator`,
+    /// e.g. to put hot stacks in a faster memory region.
+    pub fn new_in<A: Allocator + Send + Sync + 'static>(
+        task_collection: Arc<TaskCollection>,
+        stack_size: usize,
+        allocator: A,
+    ) -> Pin<Box<Self>> {
+        Self::new_in_shared(task_collection, stack_size, Arc::new(allocator))
+    }
+
+    /// Like `new_in`, the allocator is shared with other executors.
+    pub(crate) fn new_in_shared(
+        task_collection: Arc<TaskCollection>,
+        stack_size: usize,
+        allocator: Arc<dyn Allocator + Send + Sync>,
+    ) -> Pin<Box<Self>> {
+        Self::build(
+            task_collection,
+            DEFAULT_POLLING_QUANTUM,
+            stack_size,
+            allocator,
+        )
+    }
+
+    /// Create an executor which polls up to `polling_quantum` tasks before yielding to
+    /// the weak executors. The default is 1, for latency sensitive workloads, high-throughput
+    /// ones may use 8-16.
+    pub fn with_polling_quantum(
+        task_collection: Arc<TaskCollection>,
+        polling_quantum: usize,
+    ) -> Pin<Box<Self>> {
+        Self::build(
+            task_collection,
+            polling_quantum,
+            STACK_SIZE,
+            Arc::new(Global),
+        )
+    }
+
+    fn build(
+        task_collection: Arc<TaskCollection>,
+        polling_quantum: usize,
+        stack_size: usize,
+        stack_allocator: Arc<dyn Allocator + Send + Sync>,
+    ) -> Pin<Box<Self>> {
+        debug_assert!(polling_quantum > 0);
+        debug_assert!(stack_size % STACK_ALIGN == 0 && stack_size >= 4096);
+        // zeroed, so that a task never reads what the previous owner of the memory left
+        let stack: NonNull<u8> = stack_allocator
+            .allocate_zeroed(stack_layout(stack_size))
             .expect("Alloction Stack Failed.")
             .cast();
-        let stack_base = stack.as_ptr() as usize;
+        // 所有 runtime 在第一次访问 GLOBAL_RUNTIME 时一起创建, 此时 arch::cpu_id() 未必是
+        // 最终运行该 executor 的 cpu, 因此使用 task_collection 所属的 cpu.
+        let pinned_cpu = task_collection.cpu_id();
         let mut pin_executor = Pin::new(Box::new(Executor {
             id: executor_alloc_id(),
             task_collection,
-            stack_base,
+            stack,
+            stack_size,
+            stack_allocator,
             context: ExecuterContext::default(),
             #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
             context_data: ContextData::default(),
-            task_id: 0,
-            state: ExecutorState::UNUSED,
+            task_id: AtomicUsize::new(0),
+            poll_state: AtomicU8::new(PollState::IDLE as u8),
+            state: AtomicU8::new(ExecutorState::UNUSED as u8),
+            pinned_cpu,
+            polling_quantum,
         }));
 
+        pin_executor.init_stack_canary();
         pin_executor.init_stack_and_context();
 
         trace!(
@@ -68,16 +262,21 @@ impl Executor {
         pin_executor
     }
 
-    // stack layout: [executor_addr | context ]
+    // stack layout (from top to bottom): [canary | executor_addr | context | ... | canary]
     fn init_stack_and_context(&mut self) {
-        let mut stack_top = self.stack_base + STACK_SIZE;
-        let self_addr = self as *const Self as usize;
-        stack_top = unsafe { push_stack(stack_top, self_addr) };
+        let mut stack = StackBuilder::new(
+            self.stack.as_ptr(),
+            core::mem::size_of::<usize>(),
+            self.stack_size - STACK_CANARY_RESERVED,
+        );
+        // push a pointer rather than an address, so that `run_executor` gets its provenance
+        let self_ptr = self as *mut Self;
+        stack.push(self_ptr);
         #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
         {
             self.context_data = ContextData::new(
                 executor_entry as *const () as usize,
-                stack_top,
+                stack.top(),
                 crate::arch::pg_base_register(),
             );
             self.context
@@ -87,47 +286,146 @@ impl Executor {
         {
             let context_data = ContextData::new(
                 executor_entry as *const () as usize,
-                stack_top,
+                stack.top(),
                 crate::arch::pg_base_register(),
             );
-            stack_top = unsafe { push_stack(stack_top, context_data) };
-            self.context.set_context(stack_top);
+            stack.push(context_data);
+            self.context.set_context(stack.top());
+        }
+    }
+
+    fn stack_canary_addrs(&self) -> (*mut usize, *mut usize) {
+        let bottom = self.stack.as_ptr() as *mut usize;
+        let top = unsafe {
+            self.stack
+                .as_ptr()
+                .add(self.stack_size - core::mem::size_of::<usize>()) as *mut usize
+        };
+        (bottom, top)
+    }
+
+    fn init_stack_canary(&self) {
+        let (bottom, top) = self.stack_canary_addrs();
+        unsafe {
+            bottom.write_volatile(STACK_CANARY);
+            top.write_volatile(STACK_CANARY);
         }
     }
 
-    pub fn run(&mut self) {
+    fn check_stack_canary(&self) {
+        let (bottom, top) = self.stack_canary_addrs();
+        let ok = unsafe {
+            bottom.read_volatile() == STACK_CANARY && top.read_volatile() == STACK_CANARY
+        };
+        if !ok {
+            let handler = *STACK_CORRUPTION_HANDLER.lock();
+            handler(self.id, self.stack_base());
+            // the handler did not panic, re-arm the canary and go on
+            self.init_stack_canary();
+        }
+    }
+
+    // keep the frame for debugging, the compiler decides in release builds
+    #[cfg_attr(debug_assertions, inline(never))]
+    pub fn run(&self) {
+        debug_assert_eq!(
+            crate::arch::cpu_id(),
+            self.pinned_cpu,
+            "executor {} runs on a wrong cpu",
+            self.id
+        );
+        let mut quantum = self.polling_quantum;
+        let mut budget_start = budget_start_cycles();
         loop {
-            let mut task_info = self.task_collection.take_task();
+            self.check_stack_canary();
+            // re-armed before each task is taken, so that a corruption is blamed on the
+            // tasks polled since the last check
+            self.init_stack_canary();
+            crate::spawn_queue::drain_spawn_queue(&self.task_collection);
+            // peek first, scanning the pages consumes the notifications with a full barrier
+            // on each page
+            let tc = &self.task_collection;
+            let task_info = if tc.is_any_scannable() {
+                tc.take_task()
+            } else {
+                None
+            };
             /*
             if task_info.is_none() {
                 task_info = crate::runtime::steal_task_from_other_cpu();
             }
             */
-            if let Some((_key, task, waker_ref, droper)) = task_info {
+            if let Some((key, task, waker_ref, _droper)) = task_info {
                 let waker_ref = Arc::new(waker_ref);
                 let waker = woke::waker_ref(&waker_ref);
                 let mut cx = Context::from_waker(&waker);
-                waker_ref.mark_borrowed(true);
-                self.task_id = task.id();
+                // `take_task` has marked the task borrowed and running
+                self.task_id.store(task.id(), Ordering::Relaxed);
+                set_current_task(task.id());
                 debug!("running future {}:{}", self.id(), task.id());
+                #[cfg(feature = "tracing")]
+                let span = tracing::trace_span!(
+                    "task::poll",
+                    task_id = task.id(),
+                    executor_id = self.id()
+                );
+                #[cfg(feature = "tracing")]
+                let _enter = span.enter();
+                self.set_poll_state(PollState::POLLING);
                 let ret = task.poll(&mut cx);
+                self.set_poll_state(PollState::IDLE);
+                #[cfg(feature = "tracing")]
+                drop(_enter);
                 debug!("back from future {}:{}", self.id(), task.id());
-                self.task_id = 0;
-                waker_ref.mark_borrowed(false);
+                self.task_id.store(0, Ordering::Relaxed);
+                set_current_task(0);
+                // the task may have suspended itself while polled
+                let state = if waker_ref.is_suspended() {
+                    TaskState::SUSPENDED
+                } else {
+                    TaskState::RUNNABLE
+                };
+                task.replace_state(TaskState::RUNNING, state);
                 match ret {
                     Poll::Ready(()) => {
                         debug!("task over id = {}", task.id());
-                        droper.drop_by_ref();
+                        // free the slot now instead of on the next scan of dropped tasks,
+                        // `droper` is left for tasks finished outside the executor. The task
+                        // stays borrowed until it is removed, so its slot is never reused
+                        // meanwhile.
+                        self.task_collection.remove_polled_task(key, &task);
                     }
                     Poll::Pending => {
-                        // Do Nothing
+                        waker_ref.mark_borrowed(false);
                     }
                 };
-                if let ExecutorState::WEAK = self.state {
-                    self.state = ExecutorState::KILLED;
+                if self.state() == ExecutorState::WEAK {
+                    self.set_state(ExecutorState::KILLED);
                     return;
                 }
+                let budget = TIME_BUDGET.load(Ordering::Relaxed);
+                let exhausted = budget != 0
+                    && crate::clock::now_cycles().saturating_sub(budget_start) >= budget;
+                if exhausted || crate::runtime::preemption_requested() {
+                    debug!("time budget exhausted or preemption requested, yield to runtime");
+                    quantum = self.polling_quantum;
+                    self.check_stack_canary();
+                    crate::runtime::sched_yield();
+                    budget_start = budget_start_cycles();
+                    continue;
+                }
+                quantum -= 1;
+                if quantum == 0 {
+                    quantum = self.polling_quantum;
+                    if crate::runtime::get_current_runtime().weak_executor_num() != 0 {
+                        debug!("quantum exhausted, run weak executor");
+                        self.check_stack_canary();
+                        crate::runtime::sched_yield();
+                    }
+                }
             } else {
+                quantum = self.polling_quantum;
+                budget_start = budget_start_cycles();
                 let runtime = crate::runtime::get_current_runtime();
                 let task_num = runtime.task_num();
                 let weak_executor = runtime.weak_executor_num();
@@ -135,9 +433,11 @@ impl Executor {
                 // TODO: some cores may exit by mistake when we have multi-cores
                 if cfg!(feature = "baremetal-test") && task_num == 0 {
                     debug!("all done! exit and reboot");
+                    self.check_stack_canary();
                     crate::runtime::sched_yield();
                 } else if weak_executor != 0 {
                     debug!("return to runtime and run weak executor");
+                    self.check_stack_canary();
                     crate::runtime::sched_yield();
                 } else {
                     debug!("no other tasks, wait for interrupt");
@@ -150,16 +450,54 @@ impl Executor {
     // 当前是否在运行future
     // 发生supervisor时钟中断时, 若executor在运行future, 则
     // 说明该future超时, 需要切换到另一个executor来执行其他future.
+    // The state is `POLLING` only around `Task::poll`, and interrupts are off outside of
+    // it, so a preemption never sees an executor which is taking or returning a task. But
+    // there is still a window inside `Task::poll`, between the future returning and
+    // interrupts being disabled: a preemption there downgrades the executor although its
+    // poll is over. It is harmless, the weak executor finishes the poll and is killed.
     pub fn is_running_future(&self) -> bool {
-        self.task_id != 0
+        self.poll_state.load(Ordering::Relaxed) == PollState::POLLING as u8
+    }
+
+    fn set_poll_state(&self, state: PollState) {
+        self.poll_state.store(state as u8, Ordering::Relaxed);
     }
 
     pub fn killed(&self) -> bool {
-        self.state == ExecutorState::KILLED
+        self.state() == ExecutorState::KILLED
+    }
+
+    fn state(&self) -> ExecutorState {
+        ExecutorState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    // only changed by the cpu owning the executor, no read-modify-write is needed
+    fn set_state(&self, state: ExecutorState) {
+        self.state.store(state as u8, Ordering::Relaxed);
     }
 
-    pub fn mark_weak(&mut self) {
-        self.state = ExecutorState::WEAK;
+    pub(crate) fn mark_weak(&self) {
+        // a suspended executor stays suspended, it becomes weak when resumed
+        if self.state() != ExecutorState::SUSPENDED {
+            self.set_state(ExecutorState::WEAK);
+        }
+    }
+
+    pub fn suspended(&self) -> bool {
+        self.state() == ExecutorState::SUSPENDED
+    }
+
+    /// Only called from a task running on this executor, see `runtime::suspend_current_executor`.
+    pub(crate) fn suspend(&self) {
+        debug_assert!(self.is_running_future());
+        self.set_state(ExecutorState::SUSPENDED);
+    }
+
+    /// A suspended executor is always running a future, so it becomes weak after resumed.
+    pub(crate) fn resume(&self) {
+        if self.state() == ExecutorState::SUSPENDED {
+            self.set_state(ExecutorState::WEAK);
+        }
     }
 
     pub fn id(&self) -> usize {
@@ -167,24 +505,76 @@ impl Executor {
     }
 
     pub fn task_id(&self) -> usize {
-        self.task_id
+        self.task_id.load(Ordering::Relaxed)
+    }
+
+    pub fn pinned_cpu(&self) -> u8 {
+        self.pinned_cpu
+    }
+
+    pub fn stack_base(&self) -> usize {
+        self.stack.as_ptr() as usize
     }
 }
 
 impl Drop for Executor {
     fn drop(&mut self) {
+        // the executor must not be the current one, i.e. we must not run on its stack.
+        // Checked with the address of a local instead of `current_executor`, since the
+        // runtime is usually borrowed when an executor is dropped.
+        let marker = 0u8;
+        let sp = &marker as *const u8 as usize;
+        let base = self.stack.as_ptr() as usize;
+        debug_assert!(
+            !(base..base + self.stack_size).contains(&sp),
+            "executor {} dropped while running on its stack",
+            self.id
+        );
         unsafe {
-            let stack = NonNull::<u8>::new_unchecked(self.stack_base as *mut u8);
-            Global.deallocate(stack, STACK_LAYOUT);
+            self.stack_allocator
+                .deallocate(self.stack, stack_layout(self.stack_size));
         }
     }
 }
 
+/// SAFETY: an executor must only run on its `pinned_cpu`, moving it to another cpu is
+/// only valid if it is moved back before `run` is called.
 unsafe impl Send for Executor {}
 unsafe impl Sync for Executor {}
 
-pub unsafe fn push_stack<T>(stack_top: usize, val: T) -> usize {
-    let stack_top = (stack_top as *mut T).sub(1);
-    *stack_top = val;
-    stack_top as _
+/// Helper to push values onto an executor stack that grows downwards in `[bottom, top)`,
+/// which are offsets from `base`. Pointers are always derived from `base` to keep the
+/// provenance of the stack allocation.
+struct StackBuilder {
+    base: *mut u8,
+    bottom: usize,
+    top: usize,
+}
+
+impl StackBuilder {
+    fn new(base: *mut u8, bottom: usize, top: usize) -> Self {
+        debug_assert!(bottom <= top);
+        Self { base, bottom, top }
+    }
+
+    /// Push `val` below the current top, keeping it aligned for `T`.
+    fn push<T>(&mut self, val: T) -> &mut Self {
+        let base = self.base as usize;
+        let new_top = (base + self.top)
+            .checked_sub(core::mem::size_of::<T>())
+            .map(|top| (top & !(core::mem::align_of::<T>() - 1)).wrapping_sub(base))
+            .filter(|top| *top >= self.bottom && *top <= self.top)
+            .expect("executor stack overflow");
+        // SAFETY: `new_top` is inside the stack and aligned for `T`
+        unsafe {
+            (self.base.add(new_top) as *mut T).write(val);
+        }
+        self.top = new_top;
+        self
+    }
+
+    /// return the address of current top.
+    fn top(&self) -> usize {
+        self.base as usize + self.top
+    }
 }
@@ -0,0 +1,25 @@
+//! Inter-processor interrupts. Waking a task of another cpu sends an ipi to that cpu, so
+//! that it does not sleep in `wait_for_interrupt` until the next timer tick.
+//!
+//! How to send an ipi depends on the platform (APIC ICR on x86_64, SBI `send_ipi` or CLINT
+//! `msip` on riscv64, GIC SGI on aarch64), so the kernel registers the sender.
+
+use lazy_static::*;
+use lock::Mutex;
+
+lazy_static! {
+    static ref IPI_SENDER: Mutex<Option<fn(u8)>> = Mutex::new(None);
+}
+
+/// Register the function used to send an ipi to a cpu.
+pub fn set_ipi_sender(sender: fn(u8)) {
+    *IPI_SENDER.lock() = Some(sender);
+}
+
+/// Send an ipi to `target_cpu`, do nothing if no sender is registered.
+pub fn send_ipi(target_cpu: u8) {
+    let sender = *IPI_SENDER.lock();
+    if let Some(sender) = sender {
+        sender(target_cpu);
+    }
+}
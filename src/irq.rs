@@ -0,0 +1,67 @@
+//! Bridge interrupts to tasks: a task awaits `IrqFuture(irq)`, and the interrupt handler
+//! calls `notify_irq(irq)` to wake it.
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use lazy_static::*;
+use lock::Mutex;
+
+pub const MAX_IRQS: usize = 256;
+
+lazy_static! {
+    static ref IRQ_WAKERS: Vec<Mutex<Option<Waker>>> =
+        (0..MAX_IRQS).map(|_| Mutex::new(None)).collect();
+}
+
+// irqs which fired but are not consumed by an `IrqFuture` yet
+static IRQ_PENDING: [AtomicU64; MAX_IRQS / 64] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Register `waker` to be woken when `irq` fires, replacing the previous one.
+pub fn register_irq_waker(irq: usize, waker: Waker) {
+    assert!(irq < MAX_IRQS, "irq {} out of range", irq);
+    *IRQ_WAKERS[irq].lock() = Some(waker);
+}
+
+/// Called by the interrupt handler of `irq`, with interrupts disabled.
+pub fn notify_irq(irq: usize) {
+    assert!(irq < MAX_IRQS, "irq {} out of range", irq);
+    IRQ_PENDING[irq / 64].fetch_or(1 << (irq % 64), Ordering::SeqCst);
+    if let Some(waker) = IRQ_WAKERS[irq].lock().take() {
+        waker.wake();
+    }
+}
+
+fn take_pending(irq: usize) -> bool {
+    let mask = 1 << (irq % 64);
+    IRQ_PENDING[irq / 64].fetch_and(!mask, Ordering::SeqCst) & mask != 0
+}
+
+/// Resolve when `irq` fires.
+pub struct IrqFuture(pub usize);
+
+impl Future for IrqFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let irq = self.0;
+        if take_pending(irq) {
+            return Poll::Ready(());
+        }
+        register_irq_waker(irq, cx.waker().clone());
+        // the irq may fire before the waker is registered
+        if take_pending(irq) {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,59 @@
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use lock::Mutex;
+
+struct JoinState<T> {
+    output: Option<T>,
+    waker: Option<Waker>,
+}
+
+/// Wait for a task created by [`crate::spawn`] and get its output.
+///
+/// Dropping the handle detaches the task, its output is dropped when it completes.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+/// Wrap `future` into a future with `()` output which can be stored in a `Task`, its
+/// output is stored for the returned `JoinHandle`.
+pub(crate) fn join_pair<T: Send + 'static>(
+    future: impl Future<Output = T> + Send + 'static,
+) -> (impl Future<Output = ()> + Send + 'static, JoinHandle<T>) {
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        waker: None,
+    }));
+    let task_state = state.clone();
+    let task = async move {
+        let output = future.await;
+        let waker = {
+            let mut state = task_state.lock();
+            state.output = Some(output);
+            state.waker.take()
+        };
+        // wake without holding the lock
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    };
+    (task, JoinHandle { state })
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
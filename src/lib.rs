@@ -1,64 +1,118 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(allocator_api)]
-#![feature(get_mut_unchecked)]
-#![feature(generators, generator_trait)]
 #![feature(stmt_expr_attributes)]
 #![feature(atomic_mut_ptr)]
-#![feature(box_into_inner)]
 #![feature(new_uninit)]
+#![feature(box_into_pin)]
+#![feature(naked_functions)]
+#![cfg_attr(target_has_atomic = "128", feature(integer_atomics))]
 // some interfaces is still under developing
 #![allow(dead_code)]
 
-cfg_if::cfg_if! {
-  if #[cfg(target_arch = "x86_64")] {
-      #[path = "arch/x86_64/mod.rs"]
-      #[macro_use]
-      mod arch;
-  } else if #[cfg(target_arch = "riscv64")] {
-      #[path = "arch/riscv64/mod.rs"]
-      #[macro_use]
-      mod arch;
-  } else if #[cfg(target_arch = "aarch64")] {
-      #[path = "arch/aarch64/mod.rs"]
-      #[macro_use]
-      mod arch;
-  }
-}
-
-extern crate alloc;
+#[cfg(all(feature = "log", not(feature = "defmt")))]
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+mod logging;
+
+#[macro_use]
+mod arch;
+
+#[cfg(feature = "std")]
+pub use arch::bind_cpu;
+
+extern crate alloc;
+
+// tasks, wakers and executors are all shared through the heap, see LOG.md
+#[cfg(feature = "no-alloc")]
+compile_error!(
+    "the `no-alloc` feature is not supported, the scheduler requires a global allocator; \
+     pass a `task_capacity` to `ExecutorRuntime::new` or use the `heapless` feature to \
+     avoid allocating waker pages when tasks are spawned"
+);
+
+mod block_on;
+mod clock;
 mod context;
+mod event;
 mod executor;
+mod ipi;
+mod irq;
+mod join;
+mod notify;
+mod percpu;
+mod preempt_log;
+mod priority_mutex;
 mod runtime;
+mod shared;
+mod slab;
+mod spawn_queue;
 mod task_collection;
+mod timer;
 mod waker_page;
+mod yield_now;
 
-pub use runtime::{handle_timeout, run_until_idle, sched_yield, spawn, register_executor_runtime};
+pub use self::executor::{
+    set_stack_corruption_handler, set_time_budget, with_current_executor, with_current_task,
+    Executor,
+};
+pub use block_on::block_on;
+pub use clock::{
+    cycles_to_ns, now_cycles, ns_to_cycles, set_clock_source, ArchClock, ClockSource, Nanoseconds,
+};
+pub use event::{Event, EventWait};
+pub use ipi::set_ipi_sender;
+pub use irq::{notify_irq, register_irq_waker, IrqFuture, MAX_IRQS};
+pub use join::JoinHandle;
+pub use notify::{Notified, Notify};
+pub use preempt_log::{dump_preemption_log, PreemptionRecord, PREEMPTION_LOG_SIZE};
+pub use priority_mutex::{PriorityLock, PriorityMutex, PriorityMutexGuard};
+#[cfg(feature = "deadlock-detect")]
+pub use runtime::set_deadlock_hook;
+pub use runtime::{
+    cancel_children, cancel_task, dump_tasks, handle_task_panic, handle_timeout, migrate_task,
+    preempt_if_requested, preemption_latency_stats, rebalance_tasks, register_executor_runtime,
+    request_preemption, resume_executor, resume_task, run_until_idle, runtime_stats, sched_yield,
+    set_task_panic_hook, shrink_task_pages, spawn, spawn_child, spawn_detached,
+    suspend_current_executor, suspend_task, weak_executor_ids, MigrateError, RuntimeStats,
+    SpawnError, MAX_PARKED_EXECUTORS, MAX_WEAK_EXECUTORS,
+};
+pub use shared::Shared;
+pub use spawn_queue::spawn_from_irq;
+pub use timer::{process_timers, Interval, TimerHandle, TimerWheel};
+pub use yield_now::yield_now;
 
+/// Run the statements with interrupts on, and restore the interrupt state afterwards.
+///
+/// The statements must not `.await`: a future suspended in between would leave the
+/// interrupt state modified. They run in a plain closure, so that `.await` does not compile,
+/// and `return` or `?` only leave the statements.
 #[macro_export]
 macro_rules! run_with_intr_saved_on {
-    ($($statements:stmt)*) => {
+    ($($statements:stmt);* $(;)?) => {
         let enable = crate::arch::intr_get();
         if !enable {
           crate::arch::intr_on();
         }
-        $($statements)*
+        #[allow(clippy::redundant_closure_call)]
+        (|| { $($statements)* })();
         if !enable {
           crate::arch::intr_off();
         }
     };
 }
 
+/// Run the statements with interrupts off, see `run_with_intr_saved_on`.
 #[macro_export]
 macro_rules! run_with_intr_saved_off {
-    ($($statements:stmt)*) => {
+    ($($statements:stmt);* $(;)?) => {
         let enable = crate::arch::intr_get();
         if enable {
             crate::arch::intr_off();
         }
-        $($statements)*
+        #[allow(clippy::redundant_closure_call)]
+        (|| { $($statements)* })();
         if enable {
             crate::arch::intr_on();
         }
@@ -0,0 +1,53 @@
+//! Logging macros. With the default `log` feature, `trace!`..`error!` come from the `log`
+//! crate, with the `defmt` feature they are forwarded to `defmt`, which formats on the host
+//! to save code space on embedded targets. Without both features, logging is disabled.
+
+#[cfg(feature = "defmt")]
+macro_rules! trace {
+    ($($arg:tt)*) => { defmt::trace!($($arg)*) };
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! debug {
+    ($($arg:tt)*) => { defmt::debug!($($arg)*) };
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! info {
+    ($($arg:tt)*) => { defmt::info!($($arg)*) };
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! warn {
+    ($($arg:tt)*) => { defmt::warn!($($arg)*) };
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! error {
+    ($($arg:tt)*) => { defmt::error!($($arg)*) };
+}
+
+#[cfg(not(any(feature = "log", feature = "defmt")))]
+macro_rules! trace {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(not(any(feature = "log", feature = "defmt")))]
+macro_rules! debug {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(not(any(feature = "log", feature = "defmt")))]
+macro_rules! info {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(not(any(feature = "log", feature = "defmt")))]
+macro_rules! warn {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(not(any(feature = "log", feature = "defmt")))]
+macro_rules! error {
+    ($($arg:tt)*) => {};
+}
@@ -0,0 +1,133 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
+use lock::Mutex;
+
+const WAITING: u8 = 0;
+// woken by `notify_one`, the notification is passed on if the waiter is dropped
+const NOTIFIED_ONE: u8 = 1;
+// woken by `notify_waiters`
+const NOTIFIED_ALL: u8 = 2;
+
+struct Waiter {
+    state: AtomicU8,
+    waker: Mutex<Waker>,
+}
+
+struct NotifyInner {
+    // a `notify_one` without waiters is stored, the next `notified` returns at once
+    permit: bool,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+/// Notify tasks without passing any data, like `tokio::sync::Notify`.
+pub struct Notify {
+    inner: Mutex<NotifyInner>,
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(NotifyInner {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Wake the first waiting task. If no task is waiting, the notification is stored for
+    /// the next `notified`.
+    pub fn notify_one(&self) {
+        let mut inner = self.inner.lock();
+        if let Some(waiter) = inner.waiters.pop_front() {
+            drop(inner);
+            waiter.state.store(NOTIFIED_ONE, Ordering::Release);
+            waiter.waker.lock().wake_by_ref();
+        } else {
+            inner.permit = true;
+        }
+    }
+
+    /// Wake all the waiting tasks, nothing is stored if there are no waiters.
+    pub fn notify_waiters(&self) {
+        let waiters = core::mem::take(&mut self.inner.lock().waiters);
+        for waiter in waiters {
+            waiter.state.store(NOTIFIED_ALL, Ordering::Release);
+            waiter.waker.lock().wake_by_ref();
+        }
+    }
+
+    /// Wait for a notification.
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            waiter: None,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        match &this.waiter {
+            None => {
+                let mut inner = this.notify.inner.lock();
+                if inner.permit {
+                    inner.permit = false;
+                    return Poll::Ready(());
+                }
+                let waiter = Arc::new(Waiter {
+                    state: AtomicU8::new(WAITING),
+                    waker: Mutex::new(cx.waker().clone()),
+                });
+                inner.waiters.push_back(waiter.clone());
+                this.waiter = Some(waiter);
+                Poll::Pending
+            }
+            Some(waiter) => {
+                if waiter.state.load(Ordering::Acquire) != WAITING {
+                    this.waiter = None;
+                    return Poll::Ready(());
+                }
+                *waiter.waker.lock() = cx.waker().clone();
+                // the notification may arrive before the waker is updated
+                if waiter.state.load(Ordering::Acquire) != WAITING {
+                    this.waiter = None;
+                    return Poll::Ready(());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            let mut inner = self.notify.inner.lock();
+            inner.waiters.retain(|w| !Arc::ptr_eq(w, &waiter));
+            drop(inner);
+            // pass on a `notify_one` that was not consumed
+            if waiter.state.load(Ordering::Acquire) == NOTIFIED_ONE {
+                self.notify.notify_one();
+            }
+        }
+    }
+}
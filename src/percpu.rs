@@ -0,0 +1,127 @@
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut, Index};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Max number of cpus supported by the scheduler.
+pub const MAX_CPU_NUM: usize = 5;
+
+/// One `T` for each cpu. The slot of current cpu is found by `arch::cpu_id()`, which reads
+/// a cpu-local register (`gs` on x86_64, `tp` on riscv64, `MPIDR_EL1` on aarch64).
+pub struct PerCpu<T> {
+    slots: Vec<T>,
+}
+
+impl<T> PerCpu<T> {
+    /// Create `cpu_count` slots, the slot of cpu `i` is initialized with `init(i)`.
+    pub fn new(cpu_count: usize, init: impl Fn(usize) -> T) -> Self {
+        debug_assert!(cpu_count <= MAX_CPU_NUM);
+        Self {
+            slots: (0..cpu_count).map(init).collect(),
+        }
+    }
+
+    /// return the slot of current cpu.
+    pub fn get(&self) -> &T {
+        &self.slots[crate::arch::cpu_id() as usize]
+    }
+
+    /// return the slot of `cpu_id`, `None` if the cpu does not exist.
+    pub fn get_cpu(&self, cpu_id: usize) -> Option<&T> {
+        self.slots.get(cpu_id)
+    }
+
+    pub fn cpu_count(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter()
+    }
+}
+
+impl<T> Index<usize> for PerCpu<T> {
+    type Output = T;
+
+    fn index(&self, cpu_id: usize) -> &T {
+        &self.slots[cpu_id]
+    }
+}
+
+/// A value only accessed by its own cpu, e.g. the runtime of that cpu. It is borrowed
+/// without any lock: interrupts are disabled while it is borrowed, so nothing else can
+/// reach it, see [`PerCpu::borrow`].
+pub struct CpuLocal<T> {
+    value: UnsafeCell<T>,
+    // catches a second borrow on the same cpu, e.g. from a panic handler
+    borrowed: AtomicBool,
+}
+
+// SAFETY: the value is only borrowed by one cpu at a time, with interrupts off
+unsafe impl<T: Send> Sync for CpuLocal<T> {}
+
+impl<T> CpuLocal<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value: UnsafeCell::new(value),
+            borrowed: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<T> PerCpu<CpuLocal<T>> {
+    /// Borrow the value of current cpu, interrupts are disabled until the guard is dropped.
+    /// Panic if the value is borrowed already, i.e. the guard must be dropped before
+    /// switching to another executor or calling anything which borrows it again.
+    pub fn borrow(&self) -> CpuLocalGuard<'_, T> {
+        self.try_borrow()
+            .expect("cpu local value is borrowed twice")
+    }
+
+    /// Like `borrow`, but return `None` if the value is borrowed already, e.g. when a panic
+    /// handler runs on a cpu which panicked while borrowing it.
+    pub fn try_borrow(&self) -> Option<CpuLocalGuard<'_, T>> {
+        let intr_enable = crate::arch::intr_get();
+        crate::arch::intr_off();
+        let local = self.get();
+        if local.borrowed.swap(true, Ordering::Acquire) {
+            if intr_enable {
+                crate::arch::intr_on();
+            }
+            return None;
+        }
+        Some(CpuLocalGuard { local, intr_enable })
+    }
+}
+
+/// The value of a [`CpuLocal`] borrowed by current cpu, it restores the interrupt state
+/// when dropped.
+pub struct CpuLocalGuard<'a, T> {
+    local: &'a CpuLocal<T>,
+    intr_enable: bool,
+}
+
+impl<T> Deref for CpuLocalGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard is the only borrow of the value
+        unsafe { &*self.local.value.get() }
+    }
+}
+
+impl<T> DerefMut for CpuLocalGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard is the only borrow of the value
+        unsafe { &mut *self.local.value.get() }
+    }
+}
+
+impl<T> Drop for CpuLocalGuard<'_, T> {
+    fn drop(&mut self) {
+        self.local.borrowed.store(false, Ordering::Release);
+        if self.intr_enable {
+            crate::arch::intr_on();
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use lazy_static::*;
+
+use crate::percpu::{PerCpu, MAX_CPU_NUM};
+
+/// Number of preemptions kept in the log of each cpu.
+pub const PREEMPTION_LOG_SIZE: usize = 64;
+
+/// A preempted task, see [`dump_preemption_log`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PreemptionRecord {
+    pub task_id: usize,
+    pub executor_id: usize,
+    /// cycles of the clock source when the timer interrupt arrived
+    pub timestamp: u64,
+}
+
+#[derive(Default)]
+struct Slot {
+    task_id: AtomicUsize,
+    executor_id: AtomicUsize,
+    timestamp: AtomicU64,
+}
+
+/// A ring buffer of the last preemptions, written from interrupt context without locks.
+struct PreemptionLog {
+    // number of records ever written
+    head: AtomicUsize,
+    slots: Vec<Slot>,
+}
+
+impl PreemptionLog {
+    fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            slots: (0..PREEMPTION_LOG_SIZE).map(|_| Slot::default()).collect(),
+        }
+    }
+}
+
+lazy_static! {
+    static ref PREEMPTION_LOGS: PerCpu<PreemptionLog> =
+        PerCpu::new(MAX_CPU_NUM, |_| PreemptionLog::new());
+}
+
+/// Record a preemption on current cpu.
+pub(crate) fn record_preemption(task_id: usize, executor_id: usize, timestamp: u64) {
+    let log = PREEMPTION_LOGS.get();
+    let slot = &log.slots[log.head.fetch_add(1, Ordering::Relaxed) % PREEMPTION_LOG_SIZE];
+    slot.task_id.store(task_id, Ordering::Relaxed);
+    slot.executor_id.store(executor_id, Ordering::Relaxed);
+    slot.timestamp.store(timestamp, Ordering::Release);
+}
+
+/// return the last preemptions of current cpu, oldest first.
+pub fn dump_preemption_log() -> Vec<PreemptionRecord> {
+    let log = PREEMPTION_LOGS.get();
+    let head = log.head.load(Ordering::Acquire);
+    let start = head.saturating_sub(PREEMPTION_LOG_SIZE);
+    (start..head)
+        .map(|idx| {
+            let slot = &log.slots[idx % PREEMPTION_LOG_SIZE];
+            PreemptionRecord {
+                timestamp: slot.timestamp.load(Ordering::Acquire),
+                task_id: slot.task_id.load(Ordering::Relaxed),
+                executor_id: slot.executor_id.load(Ordering::Relaxed),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,225 @@
+use alloc::collections::BinaryHeap;
+use core::{
+    cell::UnsafeCell,
+    cmp::Ordering,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use lock::Mutex;
+
+use crate::task_collection::DEFAULT_PRIORITY;
+
+/// An async mutex whose waiters acquire the lock by priority instead of arrival order, so
+/// that an urgent task does not wait behind less urgent ones. A smaller `priority` is more
+/// urgent, waiters of the same priority are served in FIFO order.
+///
+/// On unlock the lock is handed to the most urgent waiter directly, so it can not be taken
+/// by a task which did not wait.
+pub struct PriorityMutex<T: ?Sized> {
+    state: Mutex<LockState>,
+    value: UnsafeCell<T>,
+}
+
+struct LockState {
+    locked: bool,
+    waiters: BinaryHeap<Waiter>,
+    // id of the waiter the lock has been handed to, which has not polled yet
+    handoff: Option<u64>,
+    next_id: u64,
+}
+
+struct Waiter {
+    priority: usize,
+    // also the arrival order
+    id: u64,
+    waker: Waker,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiter {
+    // `BinaryHeap` pops the greatest one, which is the most urgent and earliest one
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .cmp(&self.priority)
+            .then_with(|| other.id.cmp(&self.id))
+    }
+}
+
+unsafe impl<T: ?Sized + Send> Send for PriorityMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for PriorityMutex<T> {}
+
+impl<T> PriorityMutex<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            state: Mutex::new(LockState {
+                locked: false,
+                waiters: BinaryHeap::new(),
+                handoff: None,
+                next_id: 0,
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> PriorityMutex<T> {
+    /// Acquire the lock with the default priority.
+    pub fn lock(&self) -> PriorityLock<'_, T> {
+        self.lock_with_priority(DEFAULT_PRIORITY)
+    }
+
+    /// Acquire the lock, waiting behind the waiters with a smaller or equal `priority`.
+    pub fn lock_with_priority(&self, priority: usize) -> PriorityLock<'_, T> {
+        PriorityLock {
+            mutex: self,
+            priority,
+            id: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<PriorityMutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(PriorityMutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    // hand the lock to the most urgent waiter, or release it if there is none
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        match state.waiters.pop() {
+            Some(waiter) => {
+                state.handoff = Some(waiter.id);
+                drop(state);
+                waiter.waker.wake();
+            }
+            None => state.locked = false,
+        }
+    }
+}
+
+pub struct PriorityLock<'a, T: ?Sized> {
+    mutex: &'a PriorityMutex<T>,
+    priority: usize,
+    // set once the future is queued
+    id: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Future for PriorityLock<'a, T> {
+    type Output = PriorityMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.mutex.state.lock();
+        match this.id {
+            Some(id) if state.handoff == Some(id) => {
+                state.handoff = None;
+                this.id = None;
+                Poll::Ready(PriorityMutexGuard { mutex: this.mutex })
+            }
+            Some(id) => {
+                // still waiting, the task may be polled with another waker
+                let waiters = core::mem::take(&mut state.waiters);
+                state.waiters = waiters
+                    .into_iter()
+                    .map(|mut waiter| {
+                        if waiter.id == id {
+                            waiter.waker = cx.waker().clone();
+                        }
+                        waiter
+                    })
+                    .collect();
+                Poll::Pending
+            }
+            None if !state.locked => {
+                state.locked = true;
+                Poll::Ready(PriorityMutexGuard { mutex: this.mutex })
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.push(Waiter {
+                    priority: this.priority,
+                    id,
+                    waker: cx.waker().clone(),
+                });
+                this.id = Some(id);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for PriorityLock<'_, T> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut state = self.mutex.state.lock();
+        if state.handoff == Some(id) {
+            // the lock was handed to us but we are gone, pass it on
+            state.handoff = None;
+            drop(state);
+            self.mutex.unlock();
+        } else {
+            let waiters = core::mem::take(&mut state.waiters);
+            state.waiters = waiters
+                .into_iter()
+                .filter(|waiter| waiter.id != id)
+                .collect();
+        }
+    }
+}
+
+pub struct PriorityMutexGuard<'a, T: ?Sized> {
+    mutex: &'a PriorityMutex<T>,
+}
+
+impl<T: ?Sized> Deref for PriorityMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard holds the lock
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for PriorityMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard holds the lock
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for PriorityMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -1,6 +1,11 @@
 use crate::{
-    executor::Executor,
+    executor::{
+        clear_current_executor, set_current_executor, set_current_task, Executor, STACK_SIZE,
+    },
+    join::{join_pair, JoinHandle},
+    percpu::{CpuLocal, CpuLocalGuard, PerCpu, MAX_CPU_NUM},
     task_collection::*,
+    timer::{TimerWheel, DEFAULT_TIMER_RESOLUTION},
     waker_page::{DroperRef, WakerRef},
 };
 
@@ -9,12 +14,23 @@ use crate::context::Context;
 #[cfg(any(target_arch = "riscv64", target_arch = "aarch64"))]
 use crate::context::ContextData as Context;
 
-use alloc::{boxed::Box, sync::Arc, vec, vec::Vec, collections::BTreeSet};
+use alloc::alloc::{Allocator, Global};
+use alloc::{boxed::Box, collections::BTreeSet, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::{future::Future, pin::Pin};
 use lazy_static::*;
 //use spin::{Mutex, MutexGuard};
-use lock::{Mutex, MutexGuard};
+use lock::Mutex;
 
+/// Maximum number of weak executors of each runtime, i.e. of preempted tasks which have
+/// not finished their poll.
+pub const MAX_WEAK_EXECUTORS: usize = 4;
+
+/// Maximum number of suspended executors of each runtime, they are parked out of the weak
+/// executor slots. `suspend_current_executor` fails once there are so many of them.
+pub const MAX_PARKED_EXECUTORS: usize = 8;
+
+const NO_EXECUTOR: Option<Arc<Pin<Box<Executor>>>> = None;
 
 pub struct ExecutorRuntime {
     // runtime only run on this cpu
@@ -27,26 +43,123 @@ pub struct ExecutorRuntime {
     strong_executor: Arc<Pin<Box<Executor>>>,
 
     // 该 executor 在执行完一次后就会被 drop
-    weak_executors: Vec<Option<Arc<Pin<Box<Executor>>>>>,
+    // fixed size, each weak executor holds a whole stack
+    weak_executors: [Option<Arc<Pin<Box<Executor>>>>; MAX_WEAK_EXECUTORS],
+
+    // suspended weak executors are parked here instead of holding a slot, and moved back to
+    // a free slot once resumed, see `park_suspended_executors`. Fixed size as well, there
+    // is a free one for each suspended executor, see `try_suspend_current_executor`.
+    parked_executors: [Option<Arc<Pin<Box<Executor>>>>; MAX_PARKED_EXECUTORS],
+
+    // allocator of executor stacks
+    stack_allocator: Arc<dyn Allocator + Send + Sync>,
 
     // 当前正在执行的 executor
     current_executor: Option<Arc<Pin<Box<Executor>>>>,
 
     // runtime context, WARN: riscv and x86_64 use different struct
     context: Context,
+
+    // called with the id of the running task when it panics
+    task_panic_hook: Option<fn(usize)>,
+
+    // timer value when the last timer interrupt arrived, 0 if there is no pending preemption
+    preempt_start: u64,
+
+    // interrupt-to-switch latency of preemptions, in cycles
+    preempt_latency: PreemptLatency,
+
+    stats: RuntimeStats,
+
+    // timers of tasks on this cpu
+    pub(crate) timer_wheel: TimerWheel,
+
+    // consecutive timer ticks without any task to poll, see `check_deadlock`
+    #[cfg(feature = "deadlock-detect")]
+    idle_ticks: usize,
+}
+
+/// Scheduling counters of a cpu, see [`runtime_stats`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RuntimeStats {
+    /// number of tasks preempted by the timer, each one downgrades the strong executor
+    pub preemptions: usize,
+    /// number of `sched_yield` called by executors out of preemption
+    pub voluntary_yields: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PreemptLatency {
+    min: u64,
+    max: u64,
+    total: u64,
+    count: u64,
+}
+
+impl PreemptLatency {
+    fn record(&mut self, latency: u64) {
+        if self.count == 0 || latency < self.min {
+            self.min = latency;
+        }
+        if latency > self.max {
+            self.max = latency;
+        }
+        self.total += latency;
+        self.count += 1;
+    }
+
+    fn mean(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.total / self.count
+        }
+    }
 }
 
 impl ExecutorRuntime {
-    pub fn new(cpu_id: u8) -> Self {
-        let task_collection = TaskCollection::new(cpu_id);
-        let tc_clone = task_collection.clone();
+    /// Create a runtime with waker pages pre-allocated for `task_capacity` tasks. With the
+    /// `heapless` feature, it panics if they need more than `MAX_PAGES` pages.
+    pub fn new(cpu_id: u8, task_capacity: usize) -> Self {
+        Self::new_in(cpu_id, task_capacity, Global)
+    }
+
+    /// Create a runtime whose executor stacks are allocated by `stack_allocator`.
+    pub fn new_in<A: Allocator + Send + Sync + 'static>(
+        cpu_id: u8,
+        task_capacity: usize,
+        stack_allocator: A,
+    ) -> Self {
+        let task_collection = TaskCollection::with_capacity(cpu_id, task_capacity);
+        Self::with_task_collection(task_collection, Arc::new(stack_allocator))
+    }
+
+    fn with_task_collection(
+        task_collection: Arc<TaskCollection>,
+        stack_allocator: Arc<dyn Allocator + Send + Sync>,
+    ) -> Self {
+        let cpu_id = task_collection.cpu_id();
+        let strong_executor = Arc::new(Executor::new_in_shared(
+            task_collection.clone(),
+            STACK_SIZE,
+            stack_allocator.clone(),
+        ));
         ExecutorRuntime {
             cpu_id,
             task_collection,
-            strong_executor: Arc::new(Executor::new(tc_clone)),
-            weak_executors: vec![],
+            strong_executor,
+            weak_executors: [NO_EXECUTOR; MAX_WEAK_EXECUTORS],
+            parked_executors: [NO_EXECUTOR; MAX_PARKED_EXECUTORS],
+            stack_allocator,
             current_executor: None,
             context: Context::default(),
+            task_panic_hook: None,
+            preempt_start: 0,
+            preempt_latency: PreemptLatency::default(),
+            stats: RuntimeStats::default(),
+            timer_wheel: TimerWheel::new(DEFAULT_TIMER_RESOLUTION, crate::clock::now_cycles()),
+            #[cfg(feature = "deadlock-detect")]
+            idle_ticks: 0,
         }
     }
 
@@ -54,8 +167,28 @@ impl ExecutorRuntime {
         self.cpu_id
     }
 
+    // number of weak executors which can be switched to, suspended ones are not counted so
+    // that executors do not keep yielding to a runtime which has nothing to run. Resumed
+    // executors still parked are counted, the runtime moves them to a slot.
     pub(crate) fn weak_executor_num(&self) -> usize {
-        self.weak_executors.len()
+        self.weak_executors
+            .iter()
+            .flatten()
+            .chain(self.parked_executors.iter().flatten())
+            .filter(|executor| !executor.suspended())
+            .count()
+    }
+
+    // number of executors which are suspended or parked, each one needs a parking slot
+    fn parked_executor_num(&self) -> usize {
+        let suspended = self
+            .weak_executors
+            .iter()
+            .flatten()
+            .chain(core::iter::once(&self.strong_executor))
+            .filter(|executor| executor.suspended())
+            .count();
+        suspended + self.parked_executors.iter().flatten().count()
     }
 
     // return task number of current cpu.
@@ -63,24 +196,73 @@ impl ExecutorRuntime {
         self.task_collection.task_num()
     }
 
-    fn add_weak_executor(&mut self, weak_executor: Arc<Pin<Box<Executor>>>) {
-        self.weak_executors.push(Some(weak_executor));
+    // free the slots of the weak executors which have finished their task
+    fn remove_killed_executors(&mut self) {
+        for slot in self.weak_executors.iter_mut() {
+            if slot.as_ref().map_or(false, |executor| executor.killed()) {
+                *slot = None;
+            }
+        }
     }
 
-    fn downgrade_strong_executor(&mut self) {
-        // SAFETY: 只会在一个 core 上运行，不需要考虑同步问题
-        let mut old = self.strong_executor.clone();
-        unsafe {
-            Arc::get_mut_unchecked(&mut old).mark_weak();
+    // Move the suspended weak executors out of their slots, and the resumed ones back to the
+    // free slots. Otherwise once the slots are full of suspended executors, the strong
+    // executor could never be downgraded and preemption would be disabled.
+    fn park_suspended_executors(&mut self) {
+        for slot in self.weak_executors.iter_mut() {
+            if slot.as_ref().map_or(false, |executor| executor.suspended()) {
+                let executor = slot.take().unwrap();
+                park_executor(&mut self.parked_executors, executor);
+            }
+        }
+        for parked in self.parked_executors.iter_mut() {
+            if parked
+                .as_ref()
+                .map_or(false, |executor| !executor.suspended())
+            {
+                match self.weak_executors.iter_mut().find(|slot| slot.is_none()) {
+                    Some(slot) => *slot = parked.take(),
+                    None => break,
+                }
+            }
         }
-        self.add_weak_executor(old);
-        self.strong_executor = Arc::new(Executor::new(self.task_collection.clone()));
     }
 
-    // 添加一个task，它的初始状态是 notified，也就是说它可以被执行.
-    fn add_task<F: Future<Output = ()> + 'static + Send>(&self, priority: usize, future: F) -> Key {
-        debug_assert!(priority < MAX_PRIORITY);
-        self.task_collection.add_task(future)
+    fn add_weak_executor(&mut self, weak_executor: Arc<Pin<Box<Executor>>>) {
+        let slot = self
+            .weak_executors
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("no free slot for weak executor");
+        *slot = Some(weak_executor);
+    }
+
+    /// Turn the strong executor, which is polling a task, into a weak one and create a new
+    /// strong executor. Return false if there are already `MAX_WEAK_EXECUTORS` weak
+    /// executors which are not suspended, then the preempted task waits in the strong
+    /// executor until the weak executors have run, see `run_until_idle`. A suspended strong
+    /// executor is always downgraded, it is parked without taking a slot.
+    fn downgrade_strong_executor(&mut self) -> bool {
+        self.remove_killed_executors();
+        self.park_suspended_executors();
+        let old = self.strong_executor.clone();
+        if old.suspended() {
+            old.mark_weak();
+            park_executor(&mut self.parked_executors, old);
+        } else if self.weak_executors.iter().all(|slot| slot.is_some()) {
+            debug!("too many weak executors, the preempted task waits for a free slot");
+            return false;
+        } else {
+            old.mark_weak();
+            self.add_weak_executor(old);
+        }
+        self.stats.preemptions += 1;
+        self.strong_executor = Arc::new(Executor::new_in_shared(
+            self.task_collection.clone(),
+            STACK_SIZE,
+            self.stack_allocator.clone(),
+        ));
+        true
     }
 
     fn remove_task(&self, key: Key) {
@@ -103,6 +285,19 @@ impl ExecutorRuntime {
     }
 }
 
+// move a suspended executor to a free parking slot, there is always one since suspending
+// an executor fails when they are taken
+fn park_executor(
+    parked_executors: &mut [Option<Arc<Pin<Box<Executor>>>>],
+    executor: Arc<Pin<Box<Executor>>>,
+) {
+    let slot = parked_executors
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("no free slot for parked executor");
+    *slot = Some(executor);
+}
+
 impl Drop for ExecutorRuntime {
     fn drop(&mut self) {
         panic!("drop executor runtime!!!!");
@@ -111,28 +306,67 @@ impl Drop for ExecutorRuntime {
 
 // SAFETY: 只会在一个 core 上运行，不需要考虑同步问题
 unsafe impl Send for ExecutorRuntime {}
-unsafe impl Sync for ExecutorRuntime {}
 
 // TODO: more elegent?
 lazy_static! {
-    
-    pub static ref GLOBAL_RUNTIME: [Mutex<ExecutorRuntime>; 5] = [
-        Mutex::new(ExecutorRuntime::new(0)),
-        Mutex::new(ExecutorRuntime::new(1)),
-        Mutex::new(ExecutorRuntime::new(2)),
-        Mutex::new(ExecutorRuntime::new(3)),
-        Mutex::new(ExecutorRuntime::new(4))
-    ];
-    
+    // the task collection of each runtime, other cpus reach the tasks of a cpu through it
+    // without touching its runtime
+    static ref TASK_COLLECTIONS: PerCpu<Arc<TaskCollection>> =
+        PerCpu::new(MAX_CPU_NUM, |cpu_id| TaskCollection::new(cpu_id as u8));
+
+    // only borrowed by its own cpu, see `get_current_runtime`
+    pub static ref GLOBAL_RUNTIME: PerCpu<CpuLocal<ExecutorRuntime>> =
+        PerCpu::new(MAX_CPU_NUM, |cpu_id| {
+            CpuLocal::new(ExecutorRuntime::with_task_collection(
+                TASK_COLLECTIONS[cpu_id].clone(),
+                Arc::new(Global),
+            ))
+        });
+
     //pub static ref GLOBAL_RUNTIME: Mutex<BTreeMap<usize, Arc<Mutex<ExecutorRuntime>>>> = Mutex::new(BTreeMap::new());
     pub static ref ACTIVE_CPUS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+    // set by `request_preemption`, cleared by `sched_yield`
+    static ref PREEMPTION_REQUESTED: PerCpu<AtomicBool> =
+        PerCpu::new(MAX_CPU_NUM, |_| AtomicBool::new(false));
+}
+
+#[cfg(feature = "deadlock-detect")]
+const DEFAULT_DEADLOCK_TICKS: usize = 1000;
+
+#[cfg(feature = "deadlock-detect")]
+fn default_deadlock_hook() {
+    panic!("potential deadlock detected");
+}
+
+#[cfg(feature = "deadlock-detect")]
+lazy_static! {
+    // (hook, number of idle timer ticks before it is called)
+    static ref DEADLOCK_HOOK: Mutex<(fn(), usize)> =
+        Mutex::new((default_deadlock_hook, DEFAULT_DEADLOCK_TICKS));
+}
+
+/// Call `hook` when a cpu has tasks, but none of them is notified and no timer is pending
+/// for `idle_ticks` consecutive timer ticks. The default hook panics.
+#[cfg(feature = "deadlock-detect")]
+pub fn set_deadlock_hook(hook: fn(), idle_ticks: usize) {
+    // the hook is read by the timer interrupt
+    super::run_with_intr_saved_off! {
+        *DEADLOCK_HOOK.lock() = (hook, idle_ticks.max(1))
+    }
 }
 
 // A cpu should call this function to initialize the ExecutorRuntime on this cpu.
 pub fn register_executor_runtime() {
     let mut active_cpus = ACTIVE_CPUS.lock();
     //let mut global_rt = GLOBAL_RUNTIME.lock();
+    crate::arch::check_cpu_id();
     let cpu_id = crate::arch::cpu_id() as usize;
+    // ids of other cpus would alias in `GLOBAL_RUNTIME` and in the cpu field of keys
+    assert!(
+        cpu_id < MAX_CPU_NUM && cpu_id < 1 << CPU_BITS,
+        "cpu id {} is out of range",
+        cpu_id
+    );
     info!("register_executor_runtime on cpu {}", cpu_id);
     active_cpus.insert(cpu_id);
     //global_rt.insert(cpu_id as usize, Arc::new(Mutex::new(ExecutorRuntime::new(cpu_id))));
@@ -155,6 +389,8 @@ pub(crate) fn steal_task_from_other_cpu() -> Option<(Key, Arc<Task>, WakerRef, D
 */
 
 // per-cpu scheduler.
+// never inlined, so that it always has its own frame in backtraces
+#[inline(never)]
 pub fn run_until_idle() -> bool {
     debug!("GLOBAL_RUNTIME.run()");
     loop {
@@ -162,8 +398,15 @@ pub fn run_until_idle() -> bool {
         let runtime_cx = runtime.get_context();
         let executor_cx = runtime.strong_executor.context.get_context();
         debug!("switch idle -> {}", runtime.strong_executor.id());
+        trace_switch(0, runtime.strong_executor.id());
         runtime.current_executor = Some(runtime.strong_executor.clone());
-        // 释放保护 global_runtime 的锁
+        set_current_executor(&runtime.strong_executor);
+        set_current_task(runtime.strong_executor.task_id());
+        // 释放 global_runtime
+        // The runtime is not borrowed across `switch`, the executor borrows it again in
+        // `sched_yield`. Only this cpu changes the executors of its runtime, other cpus
+        // reach its task collection through `TASK_COLLECTIONS`, which is synchronized
+        // itself. `current_executor` keeps the executor alive.
         drop(runtime);
         debug!("run strong executor");
         switch(runtime_cx, executor_cx);
@@ -173,67 +416,146 @@ pub fn run_until_idle() -> bool {
         // 加到 weak_exector 中。
         runtime = get_current_runtime();
         runtime.current_executor = None;
+        clear_current_executor();
         if cfg!(feature = "baremetal-test") && runtime.task_num() == 0 {
             return false;
         }
         // 只有 strong_executor 主动 yield 时, 才会执行运行 weak_executor;
-        if runtime.strong_executor.is_running_future() {
-            runtime.downgrade_strong_executor();
+        // If all the slots are taken by weak executors, the preempted task waits in the
+        // strong executor: the weak executors run first, until one of them finishes and
+        // frees its slot, then the strong executor is switched back to.
+        if runtime.strong_executor.is_running_future() && runtime.downgrade_strong_executor() {
             continue;
         }
         // 遍历全部的 weak_executor
-        if runtime.weak_executors.is_empty() {
+        runtime.remove_killed_executors();
+        runtime.park_suspended_executors();
+        if runtime.weak_executor_num() == 0 {
             drop(runtime);
             continue;
         }
         debug!("run weak executor");
-        runtime
-            .weak_executors
-            .retain(|executor| executor.is_some() && !executor.as_ref().unwrap().killed());
-        for idx in 0..runtime.weak_executors.len() {
+        // Slots never move, and killed executors are only freed above, so the indices stay
+        // valid although the lock is released in each iteration. A slot is re-read after
+        // relocking, an executor killed meanwhile is skipped.
+        for idx in 0..MAX_WEAK_EXECUTORS {
             if let Some(executor) = &runtime.weak_executors[idx] {
-                if executor.killed() {
+                if executor.killed() || executor.suspended() {
                     continue;
                 }
                 let executor = executor.clone();
                 let executor_ctx = executor.context.get_context();
                 debug!("switch idle -> {}", executor.id());
+                trace_switch(0, executor.id());
+                // the executor resumes polling the task it was preempted in
+                set_current_task(executor.task_id());
+                set_current_executor(&executor);
                 runtime.current_executor = Some(executor);
                 drop(runtime);
                 switch(runtime_cx as _, executor_ctx as _);
                 runtime = get_current_runtime();
                 runtime.current_executor = None;
+                clear_current_executor();
             }
         }
     }
 }
 
-pub fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// failed to allocate the task
+    OutOfMemory,
+    /// the cpu to spawn on does not exist
+    InvalidCpu,
+}
+
+impl From<InsertError> for SpawnError {
+    fn from(err: InsertError) -> Self {
+        match err {
+            InsertError::OutOfMemory => SpawnError::OutOfMemory,
+        }
+    }
+}
+
+/// Spawn a task and return a [`JoinHandle`] to wait for its output.
+pub fn spawn<T: Send + 'static>(
+    future: impl Future<Output = T> + Send + 'static,
+) -> Result<JoinHandle<T>, SpawnError> {
+    let (task, handle) = join_pair(future);
+    spawn_detached(task)?;
+    Ok(handle)
+}
+
+/// Spawn a task whose output is not needed.
+pub fn spawn_detached(future: impl Future<Output = ()> + Send + 'static) -> Result<(), SpawnError> {
+    let mut ret = Ok(());
     super::run_with_intr_saved_off! {
         //spawn_task(future, None, Some(crate::arch::cpu_id() as _))
-        spawn_task(future, None, None)
+        ret = spawn_task(future, None, None)
+    }
+    ret
+}
+
+/// Spawn a child task on the cpu running current task, e.g. to share its cache. The child
+/// is cancelled with current task by [`cancel_task`]. Like `spawn`, it runs with interrupts
+/// off, since it borrows the runtime and locks the task collection which `handle_timeout`
+/// may also take.
+pub fn spawn_child<T: Send + 'static>(
+    future: impl Future<Output = T> + Send + 'static,
+) -> Result<JoinHandle<T>, SpawnError> {
+    let (task, handle) = join_pair(future);
+    let mut ret = Ok(());
+    super::run_with_intr_saved_off! {
+        let runtime = get_current_runtime();
+        // the running task never moves to another cpu, so the task collection stays valid
+        let task_collection = runtime.task_collection.clone();
+        let parent_id = runtime
+            .current_executor
+            .as_ref()
+            .map_or(0, |executor| executor.task_id());
+        drop(runtime);
+        ret = if parent_id == 0 {
+            task_collection.add_task(task).map(|_| ())
+        } else {
+            task_collection.add_child_task(task, parent_id).map(|_| ())
+        }
     }
+    ret?;
+    Ok(handle)
 }
 
 /// Spawn a coroutine with `priority` and `cpu_id`
-/// Default priority: DEFAULT_PRIORITY
-/// Default cpu_id: the cpu with fewest number of tasks
+/// Default priority: DEFAULT_PRIORITY, which is the only one supported for now, other
+/// priorities are ignored
+/// Default cpu_id: the cpu with fewest number of tasks, best effort when spawning
+/// concurrently
 pub fn spawn_task(
     future: impl Future<Output = ()> + Send + 'static,
     priority: Option<usize>,
     cpu_id: Option<usize>,
-) {
+) -> Result<(), SpawnError> {
     debug!("try to spawn {:?} {:?}", priority, cpu_id);
     let priority = priority.unwrap_or(DEFAULT_PRIORITY);
-    let runtime = if let Some(cpu_id) = cpu_id {
-        &GLOBAL_RUNTIME[cpu_id]
+    debug_assert!(priority < MAX_PRIORITY);
+    let task_collection = if let Some(cpu_id) = cpu_id {
+        match TASK_COLLECTIONS.get_cpu(cpu_id) {
+            Some(task_collection) => task_collection,
+            None => {
+                warn!("spawn on invalid cpu {}", cpu_id);
+                return Err(SpawnError::InvalidCpu);
+            }
+        }
         //GLOBAL_RUNTIME.lock().get(&cpu_id).unwrap().clone()
     } else {
+        // The task numbers are read one by one without a consistent snapshot, other cpus may
+        // add tasks to the chosen cpu before this one does. The race is benign: it only makes
+        // the load a bit unbalanced, which `rebalance_tasks` corrects. Locking all collections
+        // at once is not worth it on this path.
         ACTIVE_CPUS
             .lock()
             .iter()
-            .map(|cpu_id| &GLOBAL_RUNTIME[*cpu_id])
-            .min_by_key(|runtime| runtime.lock().task_num())
+            .map(|cpu_id| &TASK_COLLECTIONS[*cpu_id])
+            .min_by_key(|task_collection| task_collection.task_num())
             .unwrap()
         /*
         GLOBAL_RUNTIME
@@ -244,61 +566,411 @@ pub fn spawn_task(
             .clone()
         */
     };
-    warn!("task spawned on cpu {}", runtime.lock().cpu_id());
-    runtime.lock().add_task(priority, future);
+    warn!("task spawned on cpu {}", task_collection.cpu_id());
+    task_collection.add_task(future)?;
+    Ok(())
+}
+
+/// Release the trailing waker pages of current cpu which no longer hold any task, return
+/// the number of released pages. Long-running systems may call it after many tasks have
+/// exited. Live tasks are never moved to lower slots, see `FutureCollection::shrink_pages`.
+pub fn shrink_task_pages() -> usize {
+    let mut released = 0;
+    super::run_with_intr_saved_off! {
+        let task_collection = get_current_runtime().task_collection.clone();
+        released = task_collection.shrink_pages()
+    }
+    released
+}
+
+/// Move notified tasks from the cpu with most tasks to the cpu with fewest tasks, if the
+/// difference of their task numbers exceeds `threshold`. Tasks being polled are not moved.
+/// Return the number of migrated tasks.
+pub fn rebalance_tasks(threshold: usize) -> usize {
+    let mut migrated = 0;
+    super::run_with_intr_saved_off! {
+        migrated = rebalance_tasks_inner(threshold)
+    }
+    migrated
+}
+
+fn rebalance_tasks_inner(threshold: usize) -> usize {
+    let collections: Vec<(usize, Arc<TaskCollection>)> = ACTIVE_CPUS
+        .lock()
+        .iter()
+        .map(|cpu_id| (*cpu_id, TASK_COLLECTIONS[*cpu_id].clone()))
+        .collect();
+    let busiest = collections.iter().max_by_key(|(_, tc)| tc.task_num());
+    let idlest = collections.iter().min_by_key(|(_, tc)| tc.task_num());
+    let (from, to) = match (busiest, idlest) {
+        (Some(from), Some(to)) if from.0 != to.0 => (&from.1, &to.1),
+        _ => return 0,
+    };
+    let imbalance = from.task_num().saturating_sub(to.task_num());
+    if imbalance <= threshold {
+        return 0;
+    }
+    let mut migrated = 0;
+    for _ in 0..imbalance / 2 {
+        let task = match from.detach_any_task() {
+            Some(task) => task,
+            None => break,
+        };
+        if to.attach_task(task.clone()).is_err() {
+            // put it back, this can not fail since its slot was just freed
+            from.attach_task(task)
+                .expect("failed to put back a task which could not be migrated");
+            break;
+        }
+        migrated += 1;
+    }
+    debug!("rebalance: migrated {} tasks", migrated);
+    migrated
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrateError {
+    /// no task has the given id
+    NoSuchTask,
+    /// the target cpu is not active
+    InvalidCpu,
+    /// the task is being polled
+    TaskRunning,
+    /// failed to insert the task into the target cpu
+    OutOfMemory,
+}
+
+/// Move the task `task_id` to `target_cpu`.
+pub fn migrate_task(task_id: usize, target_cpu: u8) -> Result<(), MigrateError> {
+    let mut ret = Ok(());
+    super::run_with_intr_saved_off! {
+        ret = migrate_task_inner(task_id, target_cpu)
+    }
+    ret
+}
+
+fn migrate_task_inner(task_id: usize, target_cpu: u8) -> Result<(), MigrateError> {
+    let target_cpu = target_cpu as usize;
+    if !ACTIVE_CPUS.lock().contains(&target_cpu) {
+        return Err(MigrateError::InvalidCpu);
+    }
+    let to = TASK_COLLECTIONS[target_cpu].clone();
+    let (from, key) = find_task(task_id).ok_or(MigrateError::NoSuchTask)?;
+    if Arc::ptr_eq(&from, &to) {
+        return Ok(());
+    }
+    let task = from.detach_task(key).ok_or(MigrateError::TaskRunning)?;
+    if to.attach_task(task.clone()).is_err() {
+        // put it back, this can not fail since its slot was just freed
+        from.attach_task(task)
+            .expect("failed to put back a task which could not be migrated");
+        return Err(MigrateError::OutOfMemory);
+    }
+    Ok(())
+}
+
+// return the task collection holding the task whose id is `task_id`, and the key of it.
+// It locks the task collections, the caller must turn interrupts off.
+fn find_task(task_id: usize) -> Option<(Arc<TaskCollection>, Key)> {
+    debug_assert!(!crate::arch::intr_get());
+    TASK_COLLECTIONS
+        .iter()
+        .find_map(|tc| tc.find_task(task_id).map(|key| (tc.clone(), key)))
+}
+
+/// Cancel a task and, recursively, the children it spawned with [`spawn_child`]. The tasks
+/// are never polled again and are removed like completed ones. Return false if the task is
+/// not found, its children are cancelled anyway.
+pub fn cancel_task(task_id: usize) -> bool {
+    let mut found = false;
+    super::run_with_intr_saved_off! {
+        found = match find_task(task_id) {
+            Some((tc, key)) => tc.cancel_task(key, task_id),
+            None => false,
+        };
+        cancel_children(task_id)
+    }
+    found
+}
+
+/// Cancel the children of a task, which keeps running.
+pub fn cancel_children(parent_id: usize) {
+    super::run_with_intr_saved_off! {
+        let children: Vec<usize> = TASK_COLLECTIONS
+            .iter()
+            .flat_map(|tc| tc.take_children(parent_id))
+            .collect();
+        for child_id in children {
+            cancel_task(child_id);
+        }
+    }
+}
+
+/// Exclude a task from scheduling until [`resume_task`] is called, without removing it.
+/// Wakeups meanwhile are kept, and the task stays suspended if it is migrated. Return false
+/// if the task is not found.
+pub fn suspend_task(task_id: usize) -> bool {
+    let mut found = false;
+    super::run_with_intr_saved_off! {
+        found = match find_task(task_id) {
+            Some((tc, key)) => tc.suspend_task(key),
+            None => false,
+        }
+    }
+    found
+}
+
+pub fn resume_task(task_id: usize) -> bool {
+    let mut found = false;
+    super::run_with_intr_saved_off! {
+        found = match find_task(task_id) {
+            Some((tc, key)) => tc.resume_task(key),
+            None => false,
+        }
+    }
+    found
 }
 
 /// check whether the running coroutine of current cpu time out, if yes, we will
 /// switch to currrent cpu runtime that would create a new executor to run other
 /// coroutines.
+/// Called by the timer interrupt, other interrupts use `request_preemption`.
 pub fn handle_timeout() {
     debug!("handle kernel timeout");
     super::run_with_intr_saved_off! {
+        check_deadlock();
+        record_preempted_task();
+        get_current_runtime().preempt_start = crate::clock::now_cycles();
+        crate::timer::process_timers();
         sched_yield()
     }
 }
 
+/// Ask to preempt the task running on current cpu, e.g. from the handler of a device
+/// interrupt whose task is more urgent. It only sets a flag without taking any lock, so it
+/// can be called from any interrupt handler, with interrupts on or off. The task is
+/// preempted by `preempt_if_requested`, or when its poll returns.
+pub fn request_preemption() {
+    PREEMPTION_REQUESTED.get().store(true, Ordering::Release);
+}
+
+pub(crate) fn preemption_requested() -> bool {
+    PREEMPTION_REQUESTED.get().load(Ordering::Acquire)
+}
+
+/// Preempt the running task if `request_preemption` was called. Like `handle_timeout`, it
+/// must be called from an interrupt handler, at the end of it, on the stack of the
+/// interrupted executor.
+pub fn preempt_if_requested() {
+    if !preemption_requested() {
+        return;
+    }
+    debug!("handle preemption request");
+    super::run_with_intr_saved_off! {
+        record_preempted_task();
+        get_current_runtime().preempt_start = crate::clock::now_cycles();
+        sched_yield()
+    }
+}
+
+// log the task running when the timer interrupt arrives, for post-mortem analysis
+fn record_preempted_task() {
+    let (executor_id, task_id) = get_current_executor_id();
+    if task_id != 0 {
+        crate::preempt_log::record_preemption(task_id, executor_id, crate::clock::now_cycles());
+    }
+}
+
+/// Count the timer ticks during which current cpu has nothing to do but still has tasks,
+/// which may never be woken. Call the deadlock hook when there are too many of them.
+///
+/// It runs in the timer interrupt, which may have interrupted a holder of the task
+/// collection locks, so they are only tried. A locked collection is being used, the cpu
+/// is not idle then.
+#[cfg(feature = "deadlock-detect")]
+fn check_deadlock() {
+    let mut runtime = get_current_runtime();
+    let running = runtime
+        .current_executor
+        .as_ref()
+        .map_or(false, |executor| executor.is_running_future());
+    if running
+        || runtime.task_num() == 0
+        || runtime.task_collection.try_is_any_notified() != Some(false)
+        || !runtime.timer_wheel.is_empty()
+    {
+        runtime.idle_ticks = 0;
+        return;
+    }
+    runtime.idle_ticks += 1;
+    let (hook, threshold) = *DEADLOCK_HOOK.lock();
+    if runtime.idle_ticks == threshold {
+        drop(runtime);
+        hook();
+    }
+}
+
+#[cfg(not(feature = "deadlock-detect"))]
+#[inline(always)]
+fn check_deadlock() {}
+
+/// return (min, mean, max) interrupt-to-switch latency of preemptions on current cpu, in
+/// cycles of the clock source.
+pub fn preemption_latency_stats() -> (u64, u64, u64) {
+    let latency = get_current_runtime().preempt_latency;
+    (latency.min, latency.mean(), latency.max)
+}
+
 /// 运行executor.run()
 #[no_mangle]
-pub(crate) fn run_executor(executor_addr: usize) {
-    let mut p = unsafe { Box::from_raw(executor_addr as *mut Executor) };
+pub(crate) fn run_executor(executor: *mut Executor) {
+    // the executor is owned by the `Arc` in runtime, only borrow it here. It is borrowed
+    // shared, `with_current_executor` lends it to the tasks it polls.
+    let p = unsafe { &*executor };
     p.run();
     // Weak executor may return
     let runtime = get_current_runtime();
     let executor_cx = p.context.get_context();
     let runtime_cx = runtime.get_context();
     debug!("executor all done! switch {} -> idle", p.id());
+    trace_switch(p.id(), 0);
     drop(runtime);
     switch(executor_cx as _, runtime_cx as _);
     unreachable!();
 }
 
+/// return the scheduling counters of current cpu.
+pub fn runtime_stats() -> RuntimeStats {
+    get_current_runtime().stats
+}
+
+/// Suspend the executor running current task and switch to runtime. The executor will
+/// not be scheduled until [`resume_executor`] is called with its id. Suspended executors
+/// do not count against `MAX_WEAK_EXECUTORS`, they are parked instead, until they are
+/// resumed and a slot is free.
+///
+/// Return false without suspending if it is not called from a task, or if there are
+/// `MAX_PARKED_EXECUTORS` parked or suspended executors already.
+pub fn suspend_current_executor() -> bool {
+    let mut suspended = false;
+    super::run_with_intr_saved_off! {
+        suspended = try_suspend_current_executor()
+    }
+    suspended
+}
+
+fn try_suspend_current_executor() -> bool {
+    let runtime = get_current_runtime();
+    let executor = match runtime.current_executor.as_ref() {
+        Some(executor) if executor.is_running_future() => executor.clone(),
+        _ => return false,
+    };
+    if runtime.parked_executor_num() >= MAX_PARKED_EXECUTORS {
+        return false;
+    }
+    executor.suspend();
+    drop(runtime);
+    sched_yield();
+    true
+}
+
+/// Resume a suspended executor of current cpu, return false if it is not found.
+pub fn resume_executor(executor_id: usize) -> bool {
+    let runtime = get_current_runtime();
+    let weak_executors = runtime.weak_executors.iter().flatten();
+    for executor in weak_executors.chain(runtime.parked_executors.iter().flatten()) {
+        if executor.id() == executor_id {
+            executor.resume();
+            return true;
+        }
+    }
+    false
+}
+
 /// switch to runtime, which would select an appropriate executor to run.
 pub fn sched_yield() {
-    let runtime = get_current_runtime();
+    PREEMPTION_REQUESTED.get().store(false, Ordering::Release);
+    let mut runtime = get_current_runtime();
+    if runtime.preempt_start != 0 {
+        let latency = crate::clock::now_cycles().saturating_sub(runtime.preempt_start);
+        runtime.preempt_start = 0;
+        runtime.preempt_latency.record(latency);
+    } else {
+        runtime.stats.voluntary_yields += 1;
+    }
     if let Some(executor) = runtime.current_executor.as_ref() {
         let executor_cx = executor.context.get_context();
         debug!("switch {} -> idle", executor.id());
+        trace_switch(executor.id(), 0);
         let runtime_cx = runtime.get_context();
         drop(runtime);
         switch(executor_cx, runtime_cx);
     }
 }
 
+/// emit a tracing event for a context switch, executor id 0 stands for the runtime (idle).
+#[inline(always)]
+fn trace_switch(_from: usize, _to: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::event!(
+        tracing::Level::DEBUG,
+        from = _from,
+        to = _to,
+        "context_switch"
+    );
+}
+
 pub(crate) fn switch(from_ctx: usize, to_ctx: usize) {
     unsafe {
         crate::arch::switch(from_ctx as _, to_ctx as _);
     }
 }
 
-/// return runtime `MutexGuard` of current cpu.
-pub(crate) fn get_current_runtime() -> MutexGuard<'static, ExecutorRuntime> {
-    GLOBAL_RUNTIME[crate::arch::cpu_id() as usize].lock()
+/// Borrow the runtime of current cpu, interrupts are disabled until it is dropped.
+pub(crate) fn get_current_runtime() -> CpuLocalGuard<'static, ExecutorRuntime> {
+    GLOBAL_RUNTIME.borrow()
     //let cpu_id = crate::arch::cpu_id() as usize;
     //let current_runtime = GLOBAL_RUNTIME.lock().get(&cpu_id).unwrap().clone();
     //current_runtime.lock()
 }
 
+/// Register a hook on the runtime of current cpu, which is called with the id of the
+/// running task by [`handle_task_panic`].
+pub fn set_task_panic_hook(hook: fn(usize)) {
+    get_current_runtime().task_panic_hook = Some(hook);
+}
+
+/// Should be called by the kernel panic handler before it aborts. If the panic happened
+/// inside a task, the registered task panic hook is called with the task id.
+///
+/// The runtime is borrowed with `try_borrow`, since the panic may happen while it is
+/// borrowed.
+pub fn handle_task_panic() {
+    let runtime = match GLOBAL_RUNTIME.try_borrow() {
+        Some(runtime) => runtime,
+        None => return,
+    };
+    let task_id = runtime
+        .current_executor
+        .as_ref()
+        .map(|executor| executor.task_id())
+        .unwrap_or(0);
+    let hook = runtime.task_panic_hook;
+    drop(runtime);
+    if let (Some(hook), true) = (hook, task_id != 0) {
+        hook(task_id);
+    }
+}
+
+/// Log the state of the waker pages of all cpus, see `TaskCollection::dump`. Like
+/// `handle_task_panic`, it may be called from a panic handler: the runtimes are not
+/// touched, and the locked collections are skipped.
+pub fn dump_tasks() {
+    for task_collection in TASK_COLLECTIONS.iter() {
+        task_collection.dump();
+    }
+}
+
 #[allow(dead_code)]
 // Just for debug
 pub fn get_current_executor_id() -> (usize, usize) {
@@ -309,3 +981,17 @@ pub fn get_current_executor_id() -> (usize, usize) {
         (0, 0)
     }
 }
+
+/// return `(executor_id, task_id)` of the weak executors of current cpu, including the
+/// suspended ones and the killed ones which are not cleaned up yet, e.g. to find the tasks
+/// preempted most often.
+pub fn weak_executor_ids() -> Vec<(usize, usize)> {
+    let runtime = get_current_runtime();
+    runtime
+        .weak_executors
+        .iter()
+        .flatten()
+        .chain(runtime.parked_executors.iter().flatten())
+        .map(|executor| (executor.id(), executor.task_id()))
+        .collect()
+}
@@ -0,0 +1,119 @@
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use lock::Mutex;
+use woke::Woke;
+
+/// A future which can be cloned and awaited by several tasks. The inner future is run only
+/// once, every clone gets a clone of its output.
+pub struct Shared<F: Future> {
+    inner: Arc<SharedInner<F>>,
+}
+
+struct SharedInner<F: Future> {
+    state: Mutex<SharedState<F>>,
+    wakers: Arc<WakerList>,
+}
+
+enum SharedState<F: Future> {
+    Idle(Pin<Box<F>>),
+    // the inner future is being polled by one of the clones
+    Polling,
+    Done(F::Output),
+}
+
+// wakers of all the tasks waiting for the output, used as the waker of the inner future
+struct WakerList(Mutex<Vec<Waker>>);
+
+impl WakerList {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&self) {
+        let wakers = core::mem::take(&mut *self.0.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Woke for WakerList {
+    fn wake_by_ref(list: &Arc<Self>) {
+        list.wake_all();
+    }
+}
+
+impl<F: Future> Shared<F>
+where
+    F::Output: Clone,
+{
+    pub fn new(future: F) -> Self {
+        Self {
+            inner: Arc::new(SharedInner {
+                state: Mutex::new(SharedState::Idle(Box::pin(future))),
+                wakers: Arc::new(WakerList(Mutex::new(Vec::new()))),
+            }),
+        }
+    }
+
+    /// Return the output if the inner future has completed.
+    pub fn peek(&self) -> Option<F::Output> {
+        match &*self.inner.state.lock() {
+            SharedState::Done(output) => Some(output.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<F: Future> Clone for Shared<F> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<F: Future> Future for Shared<F>
+where
+    F::Output: Clone,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let inner = &self.inner;
+        let mut state = inner.state.lock();
+        if let SharedState::Done(output) = &*state {
+            return Poll::Ready(output.clone());
+        }
+        inner.wakers.register(cx.waker());
+        let mut future = match core::mem::replace(&mut *state, SharedState::Polling) {
+            SharedState::Idle(future) => future,
+            // another clone is polling the inner future, it wakes us when it is done
+            _ => return Poll::Pending,
+        };
+        // do not hold the lock while polling, the inner future may poll another clone
+        drop(state);
+        let waker = woke::waker_ref(&inner.wakers);
+        let ret = future.as_mut().poll(&mut Context::from_waker(&waker));
+        let mut state = inner.state.lock();
+        match ret {
+            Poll::Ready(output) => {
+                *state = SharedState::Done(output.clone());
+                drop(state);
+                inner.wakers.wake_all();
+                Poll::Ready(output)
+            }
+            Poll::Pending => {
+                *state = SharedState::Idle(future);
+                Poll::Pending
+            }
+        }
+    }
+}
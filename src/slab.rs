@@ -0,0 +1,125 @@
+//! The slab of tasks of a `FutureCollection`. Unlike `unicycle::pin_slab::PinSlab`, its growth
+//! can fail, so that spawning a task returns an error on OOM instead of aborting.
+
+use alloc::alloc::AllocError;
+use alloc::vec::Vec;
+
+enum Entry<T> {
+    // index of the next vacant entry
+    Vacant(usize),
+    Occupied(T),
+}
+
+/// Values indexed by the key returned by `insert`. The key of a removed value is reused by
+/// a later insertion, so the keys stay dense and index the waker pages.
+pub struct Slab<T> {
+    entries: Vec<Entry<T>>,
+    // head of the list of vacant entries, `entries.len()` if there is none
+    next: usize,
+    len: usize,
+}
+
+impl<T> Slab<T> {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Pre-allocate `capacity` entries, inserting up to `capacity` values never allocates.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: Vec::with_capacity(capacity),
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Make room for one more value, so that the next `insert` does not allocate.
+    pub fn try_reserve(&mut self) -> core::result::Result<(), AllocError> {
+        if self.next == self.entries.len() {
+            self.entries.try_reserve(1).map_err(|_| AllocError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Insert a value and return its key. It only allocates if there is no room, see
+    /// `try_reserve`.
+    pub fn insert(&mut self, value: T) -> usize {
+        let key = self.next;
+        if key == self.entries.len() {
+            self.entries.push(Entry::Occupied(value));
+            self.next = key + 1;
+        } else {
+            match core::mem::replace(&mut self.entries[key], Entry::Occupied(value)) {
+                Entry::Vacant(next) => self.next = next,
+                Entry::Occupied(_) => unreachable!("the vacant list points to a value"),
+            }
+        }
+        self.len += 1;
+        key
+    }
+
+    pub fn get(&self, key: usize) -> Option<&T> {
+        match self.entries.get(key) {
+            Some(Entry::Occupied(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Remove the value of `key`, return `None` if the entry is vacant.
+    pub fn remove(&mut self, key: usize) -> Option<T> {
+        match self.entries.get(key) {
+            Some(Entry::Occupied(_)) => {}
+            _ => return None,
+        }
+        let entry = core::mem::replace(&mut self.entries[key], Entry::Vacant(self.next));
+        self.next = key;
+        self.len -= 1;
+        match entry {
+            Entry::Occupied(value) => Some(value),
+            Entry::Vacant(_) => unreachable!(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_then_insert() {
+        let mut slab = Slab::new();
+        slab.try_reserve().unwrap();
+        let capacity = slab.entries.capacity();
+        assert!(capacity >= 1);
+        let keys: Vec<usize> = (0..capacity).map(|value| slab.insert(value)).collect();
+        assert_eq!(slab.entries.capacity(), capacity, "reallocated");
+
+        // a removed key is reused without reserving
+        assert_eq!(slab.remove(keys[0]), Some(0));
+        assert_eq!(slab.remove(keys[0]), None);
+        slab.try_reserve().unwrap();
+        assert_eq!(slab.entries.capacity(), capacity);
+        assert_eq!(slab.insert(42), keys[0]);
+        assert_eq!(slab.get(keys[0]), Some(&42));
+        assert_eq!(slab.len(), capacity);
+    }
+}
@@ -0,0 +1,123 @@
+//! Spawning from interrupt handlers. An interrupt may arrive while the runtime or the task
+//! collection of its cpu is locked, so `spawn_from_irq` only pushes the future to a
+//! lock-free per-cpu queue. The executors of that cpu move the queued futures to the task
+//! collection before looking for a task to poll.
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use lazy_static::*;
+
+use crate::percpu::{PerCpu, MAX_CPU_NUM};
+use crate::runtime::SpawnError;
+use crate::task_collection::TaskCollection;
+
+type BoxedFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct Node {
+    future: BoxedFuture,
+    next: *mut Node,
+}
+
+/// A lock-free stack of futures, pushed by any context and taken as a whole by the
+/// executors of its cpu. Taking all nodes at once with `swap` avoids the ABA problem.
+struct SpawnQueue {
+    head: AtomicPtr<Node>,
+}
+
+impl SpawnQueue {
+    fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn push(&self, node: Box<Node>) {
+        let node = Box::into_raw(node);
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            // SAFETY: the node is not shared until the exchange succeeds
+            unsafe { (*node).next = head };
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Take all the queued futures, in the order they were pushed.
+    fn take_all(&self) -> Option<Box<Node>> {
+        let mut node = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+        // the stack is last in first out, reverse it
+        let mut reversed: *mut Node = ptr::null_mut();
+        while !node.is_null() {
+            // SAFETY: the nodes were leaked by `push` and are owned by us after the swap
+            unsafe {
+                let next = (*node).next;
+                (*node).next = reversed;
+                reversed = node;
+                node = next;
+            }
+        }
+        if reversed.is_null() {
+            None
+        } else {
+            Some(unsafe { Box::from_raw(reversed) })
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed).is_null()
+    }
+}
+
+// SAFETY: the nodes are only reached through the atomic head, futures are `Send`.
+unsafe impl Send for SpawnQueue {}
+unsafe impl Sync for SpawnQueue {}
+
+lazy_static! {
+    static ref SPAWN_QUEUES: PerCpu<SpawnQueue> = PerCpu::new(MAX_CPU_NUM, |_| SpawnQueue::new());
+}
+
+/// Spawn a task on current cpu from an interrupt handler. No lock is taken, the task is
+/// added to the task collection by an executor of this cpu later. It still allocates, so
+/// the global allocator must be usable in the handler.
+pub fn spawn_from_irq(future: impl Future<Output = ()> + Send + 'static) -> Result<(), SpawnError> {
+    let future: Box<dyn Future<Output = ()> + Send> =
+        Box::try_new(future).map_err(|_| SpawnError::OutOfMemory)?;
+    let node = Box::try_new(Node {
+        future: Box::into_pin(future),
+        next: ptr::null_mut(),
+    })
+    .map_err(|_| SpawnError::OutOfMemory)?;
+    SPAWN_QUEUES.get().push(node);
+    Ok(())
+}
+
+/// Move the futures queued by `spawn_from_irq` on current cpu to `task_collection`, which
+/// must belong to current cpu.
+pub(crate) fn drain_spawn_queue(task_collection: &TaskCollection) {
+    debug_assert_eq!(task_collection.cpu_id(), crate::arch::cpu_id());
+    let queue = SPAWN_QUEUES.get();
+    if queue.is_empty() {
+        return;
+    }
+    let mut next = queue.take_all();
+    while let Some(node) = next {
+        let Node { future, next: raw } = *node;
+        next = if raw.is_null() {
+            None
+        } else {
+            // SAFETY: owned by us, see `take_all`
+            Some(unsafe { Box::from_raw(raw) })
+        };
+        if task_collection.add_task(future).is_err() {
+            warn!("failed to spawn a task queued by an interrupt handler, out of memory");
+        }
+    }
+}
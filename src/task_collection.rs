@@ -1,13 +1,18 @@
-use crate::waker_page::{DroperRef, WakerPage, WakerRef, WAKER_PAGE_SIZE};
+use crate::slab::Slab;
+use crate::waker_page::{Bitmask, DroperRef, WakerPage, WakerRef, WAKER_PAGE_SIZE};
+use alloc::alloc::AllocError;
 use alloc::sync::Arc;
-use alloc::vec;
 use alloc::vec::Vec;
 use bit_iter::BitIter;
-use core::ops::{Generator, GeneratorState};
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hashbrown::HashMap;
+// must be the same type as the one used by `WakerPage`
+#[cfg(not(loom))]
+use core::sync::atomic::AtomicBool;
+#[cfg(loom)]
+use loom::sync::atomic::AtomicBool;
 //use spin::{Mutex, MutexGuard};
 use lock::{Mutex, MutexGuard};
-use unicycle::pin_slab::PinSlab;
 use {
     alloc::boxed::Box,
     core::future::Future,
@@ -23,10 +28,14 @@ pub enum TaskState {
     // BLOCKED,
     RUNNABLE,
     RUNNING,
+    // excluded from scheduling until resumed
+    SUSPENDED,
 }
 
 pub struct Task {
     id: usize,
+    // the task which spawned this one with `spawn_child`
+    parent_id: Option<usize>,
     future: Mutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
     inner: Mutex<TaskInner>,
     finish: Arc<AtomicBool>,
@@ -56,130 +65,516 @@ fn alloc_id() -> usize {
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + Send + 'static, priority: usize) -> Self {
-        Self {
+        Self::try_new(future, priority).expect("Alloction Task Failed.")
+    }
+
+    /// Like `new`, but returns an error instead of panicking if the allocation fails.
+    pub fn try_new(
+        future: impl Future<Output = ()> + Send + 'static,
+        priority: usize,
+    ) -> core::result::Result<Self, AllocError> {
+        let future: Box<dyn Future<Output = ()> + Send> = Box::try_new(future)?;
+        Ok(Self {
             id: alloc_id(),
-            future: Mutex::new(Box::pin(future)),
+            parent_id: None,
+            future: Mutex::new(Box::into_pin(future)),
             inner: Mutex::new(TaskInner {
                 priority,
                 state: TaskState::RUNNABLE,
                 intr_enable: false,
             }),
-            finish: Arc::new(AtomicBool::new(false)),
-        }
+            finish: Arc::try_new(AtomicBool::new(false))?,
+        })
     }
     pub fn poll(&self, cx: &mut Context) -> Poll<()> {
         // if self.finish.load(Ordering::Relaxed) {
         //     return Poll::Ready(());
         // }
         let mut f = self.future.lock();
-        if self.inner.lock().intr_enable {
+        // `inner` is only locked with interrupts off: a handler on this cpu may lock it too,
+        // e.g. to read the state, and would spin forever if it interrupted the holder. So
+        // the guard is dropped before enabling interrupts, and the new state is read and
+        // interrupts are disabled before locking it again. The interrupt state is only
+        // changed by this cpu, so it does not change between reading and saving it.
+        let intr_enable = self.inner.lock().intr_enable;
+        if intr_enable {
             crate::arch::intr_on();
         }
         let ret = f.as_mut().poll(cx);
-        self.inner.lock().intr_enable = crate::arch::intr_get();
+        let intr_enable = crate::arch::intr_get();
         crate::arch::intr_off();
+        self.inner.lock().intr_enable = intr_enable;
         ret
     }
 
     pub fn id(&self) -> usize {
         self.id
     }
+
+    pub fn with_parent(mut self, parent_id: Option<usize>) -> Self {
+        self.parent_id = parent_id;
+        self
+    }
+
+    pub fn parent_id(&self) -> Option<usize> {
+        self.parent_id
+    }
+
+    pub fn priority(&self) -> usize {
+        self.inner.lock().priority
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.inner.lock().state
+    }
+
+    pub fn set_state(&self, state: TaskState) {
+        self.inner.lock().state = state;
+    }
+
+    /// Set the state to `new` only if it is `current`, return whether it is set.
+    pub fn replace_state(&self, current: TaskState, new: TaskState) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.state == current {
+            inner.state = new;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertError {
+    OutOfMemory,
+}
+
+impl From<AllocError> for InsertError {
+    fn from(_: AllocError) -> Self {
+        InsertError::OutOfMemory
+    }
+}
+
+/// Maximum number of waker pages of each priority with the `heapless` feature, so each
+/// priority holds at most `MAX_PAGES * WAKER_PAGE_SIZE` tasks.
+#[cfg(feature = "heapless")]
+pub const MAX_PAGES: usize = 16;
+
+#[cfg(feature = "heapless")]
+type Pages = heapless::Vec<Arc<WakerPage>, MAX_PAGES>;
+#[cfg(not(feature = "heapless"))]
+type Pages = Vec<Arc<WakerPage>>;
+
+// Make room for one more page, fails if it cannot be allocated or `MAX_PAGES` is reached.
+#[cfg(feature = "heapless")]
+fn reserve_page(pages: &mut Pages) -> core::result::Result<(), AllocError> {
+    if pages.is_full() {
+        Err(AllocError)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "heapless"))]
+fn reserve_page(pages: &mut Pages) -> core::result::Result<(), AllocError> {
+    pages.try_reserve(1).map_err(|_| AllocError)
+}
+
+fn push_page(pages: &mut Pages, page: Arc<WakerPage>) -> core::result::Result<(), AllocError> {
+    #[cfg(feature = "heapless")]
+    return pages.push(page).map_err(|_| AllocError);
+    #[cfg(not(feature = "heapless"))]
+    {
+        pages.push(page);
+        Ok(())
+    }
 }
 
 pub struct FutureCollection {
-    pub slab: PinSlab<Arc<Task>>,
+    pub slab: Slab<Arc<Task>>,
     // pub vec: VecDeque<Key>,
-    pub pages: Vec<Arc<WakerPage>>,
+    pub pages: Pages,
     pub priority: usize,
+    pub cpu_id: u8,
+    /// Number of notified futures of the pages, which are not taken to be polled yet.
+    /// Futures notified while polled or suspended are counted too. Shared with the pages,
+    /// which update it, and with the other priorities of a `TaskCollection`.
+    pub ready_count: Arc<AtomicUsize>,
 }
 
 impl FutureCollection {
-    pub fn new(priority: usize) -> Self {
+    pub fn new(priority: usize, cpu_id: u8) -> Self {
+        Self::with_capacity(priority, cpu_id, 0)
+    }
+
+    /// Pre-allocate the slab and the waker pages for `tasks` tasks, so that inserting up to
+    /// `tasks` tasks never allocates them, e.g. when spawning from interrupt context.
+    ///
+    /// Panics if `tasks` needs more than `MAX_PAGES` pages with the `heapless` feature.
+    pub fn with_capacity(priority: usize, cpu_id: u8, tasks: usize) -> Self {
+        Self::with_ready_count(priority, cpu_id, tasks, Arc::new(AtomicUsize::new(0)))
+    }
+
+    // like `with_capacity`, the notified futures are counted in `ready_count`
+    fn with_ready_count(
+        priority: usize,
+        cpu_id: u8,
+        tasks: usize,
+        ready_count: Arc<AtomicUsize>,
+    ) -> Self {
+        let page_num = (tasks + WAKER_PAGE_SIZE - 1) / WAKER_PAGE_SIZE;
+        #[cfg(feature = "heapless")]
+        assert!(
+            page_num <= MAX_PAGES,
+            "capacity of {} tasks exceeds {} pages",
+            tasks,
+            MAX_PAGES
+        );
+        #[cfg(feature = "heapless")]
+        let mut pages = Pages::new();
+        #[cfg(not(feature = "heapless"))]
+        let mut pages = Pages::with_capacity(page_num);
+        for _ in 0..page_num {
+            let page = WakerPage::new(cpu_id, Some(ready_count.clone()));
+            push_page(&mut pages, page).expect("the pages are reserved");
+        }
         Self {
-            slab: PinSlab::new(),
+            slab: Slab::with_capacity(tasks),
             // vec: VecDeque::new(),
-            pages: vec![],
+            pages,
             priority,
+            cpu_id,
+            ready_count,
         }
     }
-    /// Our pages hold 64 contiguous future wakers, so we can do simple arithmetic to access the
+    /// Our pages hold `WAKER_PAGE_SIZE` contiguous future wakers, so we can do simple arithmetic to access the
     /// correct page as well as the index within page.
     /// Given the `key` representing a future, return a reference to that page, `Arc<WakerPage>`. And
     /// the index _within_ that page (usize).
     pub fn page(&self, key: Key) -> (&Arc<WakerPage>, usize) {
-        let (_, page_idx, subpage_idx) = unpack_key(key);
+        let (_, _, page_idx, subpage_idx) = unpack_key(key);
         (&self.pages[page_idx], subpage_idx)
     }
 
     /// Insert a future into our scheduler returning an integer key representing this future. This
     /// key is used to index into the slab for accessing the future.
-    pub fn insert<F: Future<Output = ()> + 'static + Send>(&mut self, future: F) -> Key {
-        let key = self.slab.insert(Arc::new(Task::new(future, self.priority)));
+    ///
+    /// Returns `InsertError::OutOfMemory` if the task, the slab or its waker page cannot be
+    /// allocated.
+    pub fn insert<F: Future<Output = ()> + 'static + Send>(
+        &mut self,
+        future: F,
+        parent_id: Option<usize>,
+    ) -> core::result::Result<Key, InsertError> {
+        let task = Task::try_new(future, self.priority)?.with_parent(parent_id);
+        let task = Arc::try_new(task)?;
+        self.insert_task(task)
+    }
+
+    /// Insert an existing task, e.g. a task migrated from another cpu. The task is notified.
+    pub fn insert_task(&mut self, task: Arc<Task>) -> core::result::Result<Key, InsertError> {
+        self.slab.try_reserve()?;
+        let key = self.slab.insert(task);
         // Add a new page to hold this future's status if the current page is filled.
         while key >= self.pages.len() * WAKER_PAGE_SIZE {
-            self.pages.push(WakerPage::new());
+            let page = reserve_page(&mut self.pages)
+                .and_then(|_| WakerPage::try_new(self.cpu_id, Some(self.ready_count.clone())))
+                .and_then(|page| push_page(&mut self.pages, page));
+            match page {
+                Ok(()) => {}
+                Err(_) => {
+                    self.slab.remove(key);
+                    return Err(InsertError::OutOfMemory);
+                }
+            }
         }
         let (page, subpage_idx) = self.page(key);
         page.initialize(subpage_idx);
         // self.vec.push_back(key);
-        key
+        Ok(key)
     }
 
-    pub fn remove(&mut self, key: Key) {
-        let (page, subpage_idx) = self.page(key);
+    /// Detach a task which is not being polled, so that it can be moved to another cpu.
+    /// Return `None` if the task can not be detached.
+    ///
+    /// The task leaves the slab under the lock of this collection, which `take_task` takes
+    /// too, so it can never be polled until it is attached to another collection.
+    pub fn detach(&mut self, key: Key) -> Option<Arc<Task>> {
+        let idx = unmask_priority(key);
+        // the key may be stale, and its page released by `shrink_pages`
+        let task = self.slab.get(idx)?.clone();
+        let (page, subpage_idx) = self.page(idx);
+        if page.is_borrowed(subpage_idx) {
+            return None;
+        }
+        let suspended = page.is_suspended(subpage_idx);
+        page.clear(subpage_idx);
+        self.slab.remove(idx);
+        // the bit is gone with the slot, keep it in the task state for `attach_task`
+        if suspended {
+            task.replace_state(TaskState::RUNNABLE, TaskState::SUSPENDED);
+        }
+        Some(task)
+    }
+
+    /// Find a task which can be detached.
+    pub fn find_detachable(&self) -> Option<Key> {
+        for (page_idx, page) in self.pages.iter().enumerate() {
+            let candidates = page.notified() & !page.borrowed() & !page.suspended();
+            if candidates != 0 {
+                let subpage_idx = candidates.trailing_zeros() as usize;
+                return Some(pack_key(
+                    self.cpu_id as usize,
+                    self.priority,
+                    page_idx,
+                    subpage_idx,
+                ));
+            }
+        }
+        None
+    }
+
+    /// Release the trailing waker pages which hold no task, return the number of them.
+    ///
+    /// This is not a defragmentation: live tasks are never moved, since their keys are held
+    /// by wakers and there is no generation to tell a stale key from a moved task. So only
+    /// the pages after the last live task can be reclaimed, a single long-lived task in a
+    /// high slot keeps all the pages below it. Wakers of removed tasks may still hold a
+    /// released page, waking through them has no effect.
+    pub fn shrink_pages(&mut self) -> usize {
+        let mut released = 0;
+        while let Some(page_idx) = self.pages.len().checked_sub(1) {
+            let base = page_idx * WAKER_PAGE_SIZE;
+            if (base..base + WAKER_PAGE_SIZE).any(|key| self.slab.get(key).is_some()) {
+                break;
+            }
+            self.pages.pop();
+            released += 1;
+        }
+        #[cfg(not(feature = "heapless"))]
+        if released != 0 {
+            self.pages.shrink_to_fit();
+        }
+        released
+    }
+
+    /// Remove the future corresponding to `key`, returning the removed task. The wakers of
+    /// the task have no effect afterwards, even if its slot is reused.
+    pub fn remove(&mut self, key: Key) -> Option<Arc<Task>> {
+        let idx = unmask_priority(key);
+        // the key may be stale: never remove a vacant slot, its page may be released by
+        // `shrink_pages` too
+        let task = self.slab.get(idx)?.clone();
+        let (page, subpage_idx) = self.page(idx);
         page.clear(subpage_idx);
-        self.slab.remove(unmask_priority(key));
+        self.slab.remove(idx);
+        task.finish.store(true, Ordering::SeqCst);
+        Some(task)
+    }
+
+    /// Stop scheduling the task of `key`, it is removed like a completed task. A task being
+    /// polled is removed once the executor returns it, see `WakerPage::take_dropped`.
+    /// Return false if there is no such task: the slot of a stale key may hold another
+    /// task, so the id of the task is checked too.
+    pub fn cancel(&self, key: Key, task_id: usize) -> bool {
+        let task = match self.slab.get(unmask_priority(key)) {
+            Some(task) if task.id() == task_id => task,
+            _ => return false,
+        };
+        let (page, subpage_idx) = self.page(key);
+        // the same as `WakerRef::drop_by_ref`, wakers check the flag
+        if !task.finish.swap(true, Ordering::SeqCst) {
+            page.mark_dropped(subpage_idx);
+        }
+        true
     }
 }
 
+/// Tasks of a cpu. It is shared with `Arc` between the runtime and the executors of that
+/// cpu, and may be accessed from other cpus, e.g. to migrate tasks or when a cpu is
+/// unplugged. All its mutable state is behind locks or atomics, so it is `Send + Sync`
+/// without any `unsafe impl`. The waker pages still belong to `cpu_id`: wakers send ipis
+/// to it, so tasks must be detached and attached to move to another cpu.
 pub struct TaskCollection {
-    cpu_id: u8, // Just for debug, not used
+    cpu_id: u8,
     future_collections: Vec<Mutex<FutureCollection>>,
     pub task_num: AtomicUsize,
-    generator: Option<Mutex<Pin<Box<dyn Generator<Yield = Option<Key>, Return = ()>>>>>,
+    // shared by the collections of all priorities, see `FutureCollection::ready_count`
+    ready_count: Arc<AtomicUsize>,
+    // task id -> key, used to find a task without scanning the slab
+    task_ids: Mutex<HashMap<usize, Key>>,
+    // parent task id -> ids of the children spawned on this cpu
+    children: Mutex<HashMap<usize, Vec<usize>>>,
+    iter: Mutex<TaskIter>,
 }
 
 impl TaskCollection {
     pub fn new(cpu_id: u8) -> Arc<Self> {
-        let mut task_collection = Arc::new(TaskCollection {
-            cpu_id,
-            future_collections: Vec::with_capacity(MAX_PRIORITY),
-            task_num: AtomicUsize::new(0),
-            generator: None,
-        });
-        // SAFETY: no other Arc or Weak pointers
-        let tc_clone = task_collection.clone();
-        let mut tc = unsafe { Arc::get_mut_unchecked(&mut task_collection) };
+        Self::with_capacity(cpu_id, 0)
+    }
+
+    /// Pre-allocate the waker pages for `tasks` tasks of the default priority.
+    pub fn with_capacity(cpu_id: u8, tasks: usize) -> Arc<Self> {
+        let ready_count = Arc::new(AtomicUsize::new(0));
+        let mut future_collections = Vec::with_capacity(MAX_PRIORITY);
         for priority in 0..MAX_PRIORITY {
-            tc.future_collections
-                .push(Mutex::new(FutureCollection::new(priority)));
+            let capacity = if priority == DEFAULT_PRIORITY {
+                tasks
+            } else {
+                0
+            };
+            future_collections.push(Mutex::new(FutureCollection::with_ready_count(
+                priority,
+                cpu_id,
+                capacity,
+                ready_count.clone(),
+            )));
         }
-        tc.generator = Some(Mutex::new(Box::pin(TaskCollection::generator(tc_clone))));
-        task_collection
+        // fully built before being shared, nothing is set through the `Arc` afterwards
+        Arc::new(TaskCollection {
+            cpu_id,
+            future_collections,
+            task_num: AtomicUsize::new(0),
+            ready_count,
+            task_ids: Mutex::new(HashMap::new()),
+            children: Mutex::new(HashMap::new()),
+            iter: Mutex::new(TaskIter::new(DEFAULT_PRIORITY)),
+        })
     }
 
     /// 插入一个Future, 其优先级为 DEFAULT_PRIORITY
-    pub fn add_task<F: Future<Output = ()> + 'static + Send>(&self, future: F) -> usize {
-        self.priority_add_task(DEFAULT_PRIORITY, future)
+    ///
+    /// It locks the collection, so it must not be called from an interrupt handler, which
+    /// may have interrupted the holder of the lock on this cpu. Use `spawn_from_irq` there.
+    pub fn add_task<F: Future<Output = ()> + 'static + Send>(
+        &self,
+        future: F,
+    ) -> core::result::Result<Key, InsertError> {
+        self.priority_add_task(DEFAULT_PRIORITY, future, None)
+    }
+
+    /// Add a task spawned by the task `parent_id`, it is cancelled with its parent, see
+    /// `take_children`.
+    pub fn add_child_task<F: Future<Output = ()> + 'static + Send>(
+        &self,
+        future: F,
+        parent_id: usize,
+    ) -> core::result::Result<Key, InsertError> {
+        self.priority_add_task(DEFAULT_PRIORITY, future, Some(parent_id))
     }
 
     /// remove the task correponding to the key.
     pub fn remove_task(&self, key: Key) {
-        let mut inner = self.get_mut_inner(key >> PRIORITY_SHIFT);
-        inner.remove(unmask_priority(key));
-        self.task_num.fetch_sub(1, Ordering::Relaxed);
+        let mut inner = self.key_inner(key);
+        let task = inner.remove(key);
+        // a stale key removes nothing, and must not be counted
+        if task.is_some() {
+            self.dec_task_num();
+        }
+        drop(inner);
+        if let Some(task) = task {
+            self.forget_task(&task);
+        }
+    }
+
+    /// Remove a task which has completed while polled, it is still borrowed by the executor.
+    /// The slot is checked to hold `task` before anything is cleared, so that a stale key
+    /// never removes another task reusing the slot.
+    pub fn remove_polled_task(&self, key: Key, task: &Arc<Task>) {
+        let mut inner = self.key_inner(key);
+        match inner.slab.get(unmask_priority(key)) {
+            Some(current) if Arc::ptr_eq(current, task) => {}
+            _ => return,
+        }
+        inner.remove(key);
+        self.dec_task_num();
+        drop(inner);
+        self.forget_task(task);
+    }
+
+    // called once for each task leaving this collection, `task_num` would be meaningless
+    // after wrapping around, e.g. the shutdown check on `task_num == 0` would never pass
+    fn dec_task_num(&self) {
+        let prev = self.task_num.fetch_sub(1, Ordering::Relaxed);
+        assert!(prev != 0, "task_num of cpu {} underflows", self.cpu_id);
+    }
+
+    /// Cancel the task of `key`, see `FutureCollection::cancel`.
+    pub fn cancel_task(&self, key: Key, task_id: usize) -> bool {
+        self.key_inner(key).cancel(unmask_priority(key), task_id)
+    }
+
+    /// Take the ids of the children of `parent_id` spawned on this cpu.
+    pub fn take_children(&self, parent_id: usize) -> Vec<usize> {
+        self.children.lock().remove(&parent_id).unwrap_or_default()
+    }
+
+    // remove a task which is gone from the id map and the task tree
+    fn forget_task(&self, task: &Task) {
+        self.task_ids.lock().remove(&task.id());
+        let mut children = self.children.lock();
+        children.remove(&task.id());
+        if let Some(parent_id) = task.parent_id() {
+            if let Some(siblings) = children.get_mut(&parent_id) {
+                siblings.retain(|id| *id != task.id());
+                if siblings.is_empty() {
+                    children.remove(&parent_id);
+                }
+            }
+        }
+    }
+
+    /// Release waker pages which are no longer used, see `FutureCollection::shrink_pages`.
+    pub fn shrink_pages(&self) -> usize {
+        self.future_collections
+            .iter()
+            .map(|inner| inner.lock().shrink_pages())
+            .sum()
+    }
+
+    /// return the key of the task whose id is `task_id`.
+    pub fn find_task(&self, task_id: usize) -> Option<Key> {
+        self.task_ids.lock().get(&task_id).copied()
     }
 
     fn priority_add_task<F: Future<Output = ()> + 'static + Send>(
         &self,
         priority: usize,
         future: F,
-    ) -> Key {
-        debug_assert!(priority == DEFAULT_PRIORITY);
-        let key = self.future_collections[priority].lock().insert(future);
+        parent_id: Option<usize>,
+    ) -> core::result::Result<Key, InsertError> {
+        // priority scheduling is not implemented yet: `TaskIter` only scans
+        // DEFAULT_PRIORITY, tasks of other priorities would never be polled
+        if priority != DEFAULT_PRIORITY {
+            warn!(
+                "priority {} is not supported, use the default one",
+                priority
+            );
+        }
+        let priority = DEFAULT_PRIORITY;
+        let mut inner = self.get_mut_inner(priority);
+        let key = inner.insert(future, parent_id)?;
+        let task_id = inner.slab.get(key).unwrap().id();
         debug_assert!(key < TASK_NUM_PER_PRIORITY);
+        // update task_num before releasing the lock, so that it is never stale
         self.task_num.fetch_add(1, Ordering::Relaxed);
-        key | (priority << PRIORITY_SHIFT)
+        drop(inner);
+        let key = tag_key(self.cpu_id as usize, priority, key);
+        self.task_ids.lock().insert(task_id, key);
+        if let Some(parent_id) = parent_id {
+            self.children
+                .lock()
+                .entry(parent_id)
+                .or_default()
+                .push(task_id);
+        }
+        Ok(key)
+    }
+
+    // return the future collection holding the task of `key`, which must be on this cpu
+    fn key_inner(&self, key: Key) -> MutexGuard<'_, FutureCollection> {
+        debug_assert_eq!(key_cpu(key), self.cpu_id as usize, "key of another cpu");
+        self.get_mut_inner(key_priority(key))
     }
 
     fn get_mut_inner(&self, priority: usize) -> MutexGuard<'_, FutureCollection> {
@@ -190,60 +585,296 @@ impl TaskCollection {
         self.task_num.load(Ordering::Relaxed)
     }
 
-    pub fn take_task(&self) -> Option<(Key, Arc<Task>, WakerRef, DroperRef)> {
-        let mut generator = self.generator.as_ref().unwrap().lock();
-        match generator.as_mut().resume(()) {
-            GeneratorState::Yielded(key) => {
-                if let Some(key) = key {
-                    let (priority, page_idx, subpage_idx) = unpack_key(key);
-                    let mut inner = self.get_mut_inner(priority);
-                    let task = inner.slab.get(unmask_priority(key)).unwrap().clone();
-                    let waker = inner.pages[page_idx].make_waker(subpage_idx, &task.finish);
-                    let droper = waker.clone();
-                    Some((key, task, waker, droper))
-                } else {
-                    None
+    /// Number of notified tasks which are not taken to be polled yet, in O(1), see
+    /// `FutureCollection::ready_count`.
+    pub fn ready_num(&self) -> usize {
+        self.ready_count.load(Ordering::Relaxed)
+    }
+
+    pub fn cpu_id(&self) -> u8 {
+        self.cpu_id
+    }
+
+    /// Log the bitmasks of the pages holding any task, for diagnostics from a panic handler.
+    /// The collections are taken with `try_lock`, the ones locked when the panic happened
+    /// are skipped.
+    pub fn dump(&self) {
+        for (priority, inner) in self.future_collections.iter().enumerate() {
+            let inner = match inner.try_lock() {
+                Some(inner) => inner,
+                None => {
+                    error!("cpu {} priority {}: locked", self.cpu_id, priority);
+                    continue;
+                }
+            };
+            for (page_idx, page) in inner.pages.iter().enumerate() {
+                let dump = page.debug_dump();
+                if dump.is_empty() {
+                    continue;
                 }
+                error!(
+                    "cpu {} priority {} page {}: notified {:x} dropped {:x} borrowed {:x} suspended {:x}",
+                    self.cpu_id,
+                    priority,
+                    page_idx,
+                    dump.notified,
+                    dump.dropped,
+                    dump.borrowed,
+                    dump.suspended
+                );
             }
-            _ => panic!("unexpected value from resume"),
         }
     }
 
-    pub fn generator(self: Arc<Self>) -> impl Generator<Yield = Option<Key>, Return = ()> {
-        static move || {
-            loop {
-                let priority = DEFAULT_PRIORITY;
-                loop {
-                    let mut found_key: Option<Key> = None;
-                    let mut inner = self.get_mut_inner(priority);
-                    for page_idx in 0..inner.pages.len() {
-                        let page = &inner.pages[page_idx];
-                        let notified = page.take_notified();
-                        let dropped = page.take_dropped();
-                        if notified != 0 {
-                            for subpage_idx in BitIter::from(notified) {
-                                // the key corresponding to the task
-                                found_key = Some(pack_key(priority, page_idx, subpage_idx));
-                                drop(inner);
-                                yield found_key;
-                                inner = self.get_mut_inner(priority);
-                            }
-                        }
-                        if dropped != 0 {
-                            for subpage_idx in BitIter::from(dropped) {
-                                // the key corresponding to the task
-                                let key = pack_key(priority, page_idx, subpage_idx);
-                                self.task_num.fetch_sub(1, Ordering::Relaxed);
-                                inner.remove(key);
-                            }
-                        }
-                    }
-                    if found_key.is_none() {
-                        break;
+    /// Whether any task is ready to be polled, the notifications are not consumed.
+    pub fn is_any_notified(&self) -> bool {
+        self.future_collections.iter().any(|inner| {
+            let inner = inner.lock();
+            inner.pages.iter().any(|page| page.is_any_notified())
+        })
+    }
+
+    /// Like `is_any_notified`, but never spins on a lock, e.g. in an interrupt handler which
+    /// may have interrupted the holder. Return `None` if a collection is locked.
+    pub fn try_is_any_notified(&self) -> Option<bool> {
+        for inner in self.future_collections.iter() {
+            if inner
+                .try_lock()?
+                .pages
+                .iter()
+                .any(|page| page.is_any_notified())
+            {
+                return Some(true);
+            }
+        }
+        Some(false)
+    }
+
+    /// Whether `take_task` has anything to do: a task of the scanned priority is ready, or
+    /// has completed but not been removed. Only that priority is locked, it is checked by
+    /// the executor on every iteration.
+    pub fn is_any_scannable(&self) -> bool {
+        // notifications already taken from the pages by the iterator are not lost
+        if self.iter.lock().notified != 0 {
+            return true;
+        }
+        let inner = self.get_mut_inner(DEFAULT_PRIORITY);
+        inner
+            .pages
+            .iter()
+            .any(|page| page.is_any_notified() || page.has_dropped())
+    }
+
+    /// Whether any task has completed but not been removed, which is done by `take_task`.
+    pub fn has_dropped(&self) -> bool {
+        self.future_collections.iter().any(|inner| {
+            let inner = inner.lock();
+            inner.pages.iter().any(|page| page.has_dropped())
+        })
+    }
+
+    /// Take a notified task to poll. The task is returned borrowed and `RUNNING`, the caller
+    /// must set it back to `RUNNABLE` and unmark the borrowed bit after polling it.
+    #[cfg_attr(debug_assertions, inline(never))]
+    pub fn take_task(&self) -> Option<(Key, Arc<Task>, WakerRef, DroperRef)> {
+        loop {
+            let key = self.iter.lock().next(self)?;
+            let (_, priority, page_idx, subpage_idx) = unpack_key(key);
+            let inner = self.get_mut_inner(priority);
+            // the task may have been migrated after it is notified
+            let task = match inner.slab.get(unmask_priority(key)) {
+                Some(task) => task.clone(),
+                None => continue,
+            };
+            let page = &inner.pages[page_idx];
+            // the task is being polled by another executor, keep the notification so that it
+            // is polled again after that executor is done with it
+            if task.state() != TaskState::RUNNABLE {
+                page.notify(subpage_idx);
+                continue;
+            }
+            // mark the task borrowed before releasing the lock, so that it is never handed
+            // to two executors at the same time
+            page.mark_borrowed(subpage_idx, true);
+            task.set_state(TaskState::RUNNING);
+            let waker = page.make_waker(subpage_idx, &task.finish);
+            let droper = waker.clone();
+            return Some((key, task, waker, droper));
+        }
+    }
+
+    /// Detach a task which is not being polled, see `FutureCollection::detach`.
+    pub fn detach_task(&self, key: Key) -> Option<Arc<Task>> {
+        let mut inner = self.key_inner(key);
+        let task = inner.detach(unmask_priority(key))?;
+        self.dec_task_num();
+        drop(inner);
+        self.task_ids.lock().remove(&task.id());
+        Some(task)
+    }
+
+    /// Detach any task which can be moved to another cpu.
+    pub fn detach_any_task(&self) -> Option<Arc<Task>> {
+        let key = self.get_mut_inner(DEFAULT_PRIORITY).find_detachable()?;
+        self.detach_task(key)
+    }
+
+    /// Exclude the task from scheduling without removing it, see `WakerRef::suspend`.
+    pub fn suspend_task(&self, key: Key) -> bool {
+        let inner = self.key_inner(key);
+        let task = match inner.slab.get(unmask_priority(key)) {
+            Some(task) => task.clone(),
+            None => return false,
+        };
+        let (page, subpage_idx) = inner.page(unmask_priority(key));
+        page.suspend(subpage_idx);
+        // a running task is marked suspended after it is polled
+        task.replace_state(TaskState::RUNNABLE, TaskState::SUSPENDED);
+        true
+    }
+
+    pub fn resume_task(&self, key: Key) -> bool {
+        let inner = self.key_inner(key);
+        let task = match inner.slab.get(unmask_priority(key)) {
+            Some(task) => task.clone(),
+            None => return false,
+        };
+        let (page, subpage_idx) = inner.page(unmask_priority(key));
+        task.replace_state(TaskState::SUSPENDED, TaskState::RUNNABLE);
+        // kick the cpu of this collection like a wakeup, it may be idle
+        page.make_waker(subpage_idx, &task.finish).resume();
+        true
+    }
+
+    /// Attach a task detached from another cpu, it will be polled on this cpu with the
+    /// same priority.
+    ///
+    /// The task is always notified: the wakers it registered before still refer to its
+    /// old slot, polling it again lets it register new ones. A suspended task stays
+    /// suspended, it is polled once resumed.
+    pub fn attach_task(&self, task: Arc<Task>) -> core::result::Result<Key, InsertError> {
+        let priority = task.priority();
+        let task_id = task.id();
+        let suspended = task.state() == TaskState::SUSPENDED;
+        let mut inner = self.get_mut_inner(priority);
+        let key = inner.insert_task(task)?;
+        if suspended {
+            let (page, subpage_idx) = inner.page(key);
+            page.suspend(subpage_idx);
+        }
+        self.task_num.fetch_add(1, Ordering::Relaxed);
+        drop(inner);
+        let key = tag_key(self.cpu_id as usize, priority, key);
+        self.task_ids.lock().insert(task_id, key);
+        Ok(key)
+    }
+}
+
+// a task collection must be sendable to other cpus, fail to compile if it is not
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<TaskCollection>();
+};
+
+/// Scan the waker pages of a priority and return the keys of notified tasks one by one.
+/// Dropped tasks are removed when their page is scanned.
+///
+/// The pages are scanned repeatedly until a whole pass finds no notified task, then
+/// `None` is returned once and the next call starts a new pass.
+///
+/// Each pass starts one page after the previous one, so that the tasks of low pages are
+/// not always polled first.
+struct TaskIter {
+    priority: usize,
+    // the next page to scan
+    page_idx: usize,
+    // the first page of current pass
+    start_page: usize,
+    // number of pages scanned in current pass
+    scanned: usize,
+    // the page whose notified bits are in `notified`
+    notified_page: usize,
+    // notified tasks of `notified_page` which have not been returned
+    notified: Bitmask,
+    // whether any notified task is found in current pass
+    found: bool,
+}
+
+impl TaskIter {
+    fn new(priority: usize) -> Self {
+        Self {
+            priority,
+            page_idx: 0,
+            start_page: 0,
+            scanned: 0,
+            notified_page: 0,
+            notified: 0,
+            found: false,
+        }
+    }
+
+    fn next(&mut self, tc: &TaskCollection) -> Option<Key> {
+        loop {
+            if self.notified != 0 {
+                let subpage_idx = self.notified.trailing_zeros() as usize;
+                self.notified &= self.notified - 1;
+                // the key corresponding to the task
+                return Some(pack_key(
+                    tc.cpu_id as usize,
+                    self.priority,
+                    self.notified_page,
+                    subpage_idx,
+                ));
+            }
+            // Pages may be added or released whenever the lock is not held, so the indices
+            // are checked against the length read under the lock each time. A key returned
+            // from a page released meanwhile has no task in the slab, `take_task` skips it.
+            let mut inner = tc.get_mut_inner(self.priority);
+            let page_num = inner.pages.len();
+            if self.scanned >= page_num {
+                // a pass is over, start the next one from the next page
+                self.scanned = 0;
+                self.start_page = if page_num == 0 {
+                    0
+                } else {
+                    (self.start_page + 1) % page_num
+                };
+                self.page_idx = self.start_page;
+                if !self.found {
+                    return None;
+                }
+                self.found = false;
+                continue;
+            }
+            if self.page_idx >= page_num {
+                self.page_idx = 0;
+            }
+            let page = &inner.pages[self.page_idx];
+            let notified = page.take_notified();
+            let dropped = page.take_dropped();
+            if dropped != 0 {
+                for subpage_idx in BitIter::from(dropped) {
+                    // the key corresponding to the task
+                    let key = pack_key(
+                        tc.cpu_id as usize,
+                        self.priority,
+                        self.page_idx,
+                        subpage_idx,
+                    );
+                    // the task may have been removed by `remove_task` already
+                    if let Some(task) = inner.remove(key) {
+                        tc.dec_task_num();
+                        tc.forget_task(&task);
                     }
                 }
-                yield None;
             }
+            if notified != 0 {
+                self.found = true;
+                self.notified = notified;
+                self.notified_page = self.page_idx;
+            }
+            self.page_idx += 1;
+            self.scanned += 1;
         }
     }
 }
@@ -251,26 +882,336 @@ impl TaskCollection {
 pub use key::*;
 
 pub mod key {
+    //! A key is `[priority | cpu | page_idx | subpage_idx]` from the highest bit, so
+    //! that it identifies a task across all the cpus.
+    use crate::waker_page::WAKER_PAGE_SIZE;
+
     pub type Key = usize;
     pub const PRIORITY_SHIFT: usize = 58;
-    pub const TASK_NUM_PER_PRIORITY: usize = 1 << PRIORITY_SHIFT;
+    pub const CPU_SHIFT: usize = 55;
+    pub const CPU_BITS: usize = 3;
+    pub const TASK_NUM_PER_PRIORITY: usize = 1 << CPU_SHIFT;
     pub const MAX_PRIORITY: usize = 1 << 5;
     pub const DEFAULT_PRIORITY: usize = 4;
 
-    pub const PAGE_INDEX_SHIFT: usize = 6;
+    pub const PAGE_INDEX_SHIFT: usize = WAKER_PAGE_SIZE.trailing_zeros() as usize;
+
+    // the fields must be adjacent and must not overlap, so that packing is invertible
+    #[allow(clippy::assertions_on_constants)]
+    const _: () = assert!(CPU_SHIFT + CPU_BITS == PRIORITY_SHIFT);
+    #[allow(clippy::assertions_on_constants)]
+    const _: () = assert!(MAX_PRIORITY - 1 <= usize::MAX >> PRIORITY_SHIFT);
+    #[allow(clippy::assertions_on_constants)]
+    const _: () = assert!(PAGE_INDEX_SHIFT < CPU_SHIFT);
+
+    /// return `(cpu, priority, page_idx, subpage_idx)` of `key`.
+    pub fn unpack_key(key: Key) -> (usize, usize, usize, usize) {
+        let subpage_idx = key & (WAKER_PAGE_SIZE - 1);
+        let page_idx = unmask_priority(key) >> PAGE_INDEX_SHIFT;
+        (key_cpu(key), key_priority(key), page_idx, subpage_idx)
+    }
+
+    pub fn pack_key(cpu: usize, priority: usize, page_idx: usize, subpage_idx: usize) -> Key {
+        debug_assert!(cpu < 1 << CPU_BITS && priority < MAX_PRIORITY);
+        debug_assert!(
+            subpage_idx < WAKER_PAGE_SIZE && page_idx < TASK_NUM_PER_PRIORITY / WAKER_PAGE_SIZE
+        );
+        let key = (priority << PRIORITY_SHIFT)
+            | (cpu << CPU_SHIFT)
+            | (page_idx << PAGE_INDEX_SHIFT)
+            | subpage_idx;
+        debug_assert_eq!(unpack_key(key), (cpu, priority, page_idx, subpage_idx));
+        key
+    }
+
+    /// Add the cpu and priority to `idx`, the index of a task in its `FutureCollection`.
+    pub fn tag_key(cpu: usize, priority: usize, idx: usize) -> Key {
+        debug_assert!(idx < TASK_NUM_PER_PRIORITY);
+        pack_key(cpu, priority, 0, 0) | idx
+    }
 
-    pub fn unpack_key(key: Key) -> (usize, usize, usize) {
-        let subpage_idx = key & 0x3F;
-        let page_idx = (key << 5) >> 11;
-        let priority = key >> PRIORITY_SHIFT;
-        (priority, page_idx, subpage_idx)
+    pub fn key_cpu(key: Key) -> usize {
+        (key >> CPU_SHIFT) & ((1 << CPU_BITS) - 1)
     }
 
-    pub fn pack_key(priority: usize, page_idx: usize, subpage_idx: usize) -> Key {
-        (priority << PRIORITY_SHIFT) | (page_idx << PAGE_INDEX_SHIFT) | subpage_idx
+    pub fn key_priority(key: Key) -> usize {
+        key >> PRIORITY_SHIFT
     }
 
+    /// return the index of the task in its `FutureCollection`, without priority and cpu.
+    /// It is the index returned by `Slab::insert`, i.e. `page_idx * WAKER_PAGE_SIZE +
+    /// subpage_idx`, since pages are indexed by the slab index.
     pub fn unmask_priority(key: Key) -> usize {
-        key & !(0x1F << PRIORITY_SHIFT)
+        key & (TASK_NUM_PER_PRIORITY - 1)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use proptest::prelude::*;
+
+        const PAGE_NUM: usize = TASK_NUM_PER_PRIORITY / WAKER_PAGE_SIZE;
+
+        proptest! {
+            #[test]
+            fn pack_then_unpack(
+                cpu in 0..1usize << CPU_BITS,
+                priority in 0..MAX_PRIORITY,
+                page_idx in 0..PAGE_NUM,
+                subpage_idx in 0..WAKER_PAGE_SIZE,
+            ) {
+                let key = pack_key(cpu, priority, page_idx, subpage_idx);
+                prop_assert_eq!(unpack_key(key), (cpu, priority, page_idx, subpage_idx));
+                prop_assert_eq!(unmask_priority(key), page_idx * WAKER_PAGE_SIZE + subpage_idx);
+                prop_assert_eq!(
+                    tag_key(cpu, priority, page_idx * WAKER_PAGE_SIZE + subpage_idx),
+                    key
+                );
+            }
+
+            #[test]
+            fn unpack_then_pack(key in 0..MAX_PRIORITY << PRIORITY_SHIFT) {
+                let (cpu, priority, page_idx, subpage_idx) = unpack_key(key);
+                prop_assert_eq!(pack_key(cpu, priority, page_idx, subpage_idx), key);
+            }
+
+            #[test]
+            fn pack_is_injective(
+                cpu in 0..1usize << CPU_BITS,
+                priority in 0..MAX_PRIORITY,
+                page_idx in 0..PAGE_NUM,
+                subpage_idx in 0..WAKER_PAGE_SIZE,
+                other_cpu in 0..1usize << CPU_BITS,
+                other_priority in 0..MAX_PRIORITY,
+                other_page_idx in 0..PAGE_NUM,
+                other_subpage_idx in 0..WAKER_PAGE_SIZE,
+            ) {
+                let fields = (cpu, priority, page_idx, subpage_idx);
+                let other = (other_cpu, other_priority, other_page_idx, other_subpage_idx);
+                prop_assume!(fields != other);
+                prop_assert_ne!(
+                    pack_key(cpu, priority, page_idx, subpage_idx),
+                    pack_key(other_cpu, other_priority, other_page_idx, other_subpage_idx)
+                );
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use core::future::pending;
+
+    // the cpu of the collections, no executor runs on it
+    const CPU_ID: u8 = 3;
+
+    // take and poll the ready tasks like `Executor::run`, return the number of polls
+    fn poll_ready(tc: &TaskCollection) -> usize {
+        let mut polls = 0;
+        while let Some((_, task, waker, _)) = tc.take_task() {
+            let waker = Arc::new(waker);
+            let _ = task.poll(&mut Context::from_waker(&woke::waker_ref(&waker)));
+            task.replace_state(TaskState::RUNNING, TaskState::RUNNABLE);
+            waker.mark_borrowed(false);
+            polls += 1;
+        }
+        polls
+    }
+
+    fn wake_concurrently(waker: &WakerRef, times: usize) {
+        let threads: Vec<_> = (0..times)
+            .map(|_| {
+                let waker = waker.clone();
+                std::thread::spawn(move || waker.wake_by_ref())
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn polled_once_per_wake() {
+        let tc = TaskCollection::new(CPU_ID);
+        tc.add_task(pending()).unwrap();
+
+        // woken twice while it is polled for the first time
+        let (_, task, waker, _) = tc.take_task().unwrap();
+        wake_concurrently(&waker, 2);
+        assert!(tc.take_task().is_none(), "polled by two executors");
+        task.replace_state(TaskState::RUNNING, TaskState::RUNNABLE);
+        waker.mark_borrowed(false);
+        assert_eq!(poll_ready(&tc), 1);
+        assert_eq!(poll_ready(&tc), 0);
+
+        // woken twice while it waits
+        wake_concurrently(&waker, 2);
+        assert_eq!(poll_ready(&tc), 1);
+        assert_eq!(poll_ready(&tc), 0);
+    }
+
+    #[test]
+    fn scannable_with_notifications_taken_by_the_iterator() {
+        let tc = TaskCollection::new(CPU_ID);
+        tc.add_task(pending()).unwrap();
+        tc.add_task(pending()).unwrap();
+        // the page is scanned once, the key of the second task is kept in the iterator
+        let (_, task, waker, _) = tc.take_task().unwrap();
+        assert!(tc.is_any_scannable(), "the second task is lost");
+        task.replace_state(TaskState::RUNNING, TaskState::RUNNABLE);
+        waker.mark_borrowed(false);
+        assert_eq!(poll_ready(&tc), 1);
+        assert!(!tc.is_any_scannable());
+    }
+
+    #[test]
+    fn ready_num_of_each_collection() {
+        let tc = TaskCollection::new(CPU_ID);
+        let other = TaskCollection::new(CPU_ID);
+        let key = tc.add_task(pending()).unwrap();
+        tc.priority_add_task(DEFAULT_PRIORITY + 1, pending(), None)
+            .unwrap();
+        // a page out of any collection is not counted
+        WakerPage::new(CPU_ID, None).notify(0);
+        assert_eq!(tc.ready_num(), 2);
+        assert_eq!(other.ready_num(), 0);
+        tc.remove_task(key);
+        assert_eq!(tc.ready_num(), 1);
+        assert_eq!(poll_ready(&tc), 1);
+        assert_eq!(tc.ready_num(), 0);
+    }
+
+    #[test]
+    fn remove_and_reinsert_across_pages() {
+        let tc = TaskCollection::new(CPU_ID);
+        let tasks = 2 * WAKER_PAGE_SIZE + 2;
+        let keys: Vec<Key> = (0..tasks)
+            .map(|_| tc.add_task(pending()).unwrap())
+            .collect();
+        let page_num = tc.get_mut_inner(DEFAULT_PRIORITY).pages.len();
+        assert_eq!(page_num, 3);
+
+        let removed: Vec<Key> = keys.iter().copied().step_by(2).collect();
+        for &key in removed.iter() {
+            tc.remove_task(key);
+            // removing twice frees nothing
+            tc.remove_task(key);
+        }
+        assert_eq!(tc.task_num(), tasks - removed.len());
+        {
+            let inner = tc.get_mut_inner(DEFAULT_PRIORITY);
+            for (idx, &key) in keys.iter().enumerate() {
+                let occupied = inner.slab.get(unmask_priority(key)).is_some();
+                assert_eq!(occupied, idx % 2 == 1, "slot of key {:x}", key);
+            }
+        }
+
+        // the freed slots are reused, no page is added
+        let mut reinserted: Vec<Key> = removed
+            .iter()
+            .map(|_| tc.add_task(pending()).unwrap())
+            .collect();
+        reinserted.sort_unstable();
+        assert_eq!(reinserted, removed);
+        assert_eq!(tc.get_mut_inner(DEFAULT_PRIORITY).pages.len(), page_num);
+        assert_eq!(tc.task_num(), tasks);
+
+        for &key in keys.iter() {
+            tc.remove_task(key);
+        }
+        assert_eq!(tc.task_num(), 0);
+        let inner = tc.get_mut_inner(DEFAULT_PRIORITY);
+        assert!(keys
+            .iter()
+            .all(|&key| inner.slab.get(unmask_priority(key)).is_none()));
+    }
+
+    #[test]
+    fn stale_key_after_shrink_pages() {
+        let tc = TaskCollection::new(CPU_ID);
+        let keys: Vec<Key> = (0..WAKER_PAGE_SIZE + 1)
+            .map(|_| tc.add_task(pending()).unwrap())
+            .collect();
+        let last = *keys.last().unwrap();
+        tc.remove_task(last);
+        assert_eq!(tc.shrink_pages(), 1);
+
+        // the page of the key is released
+        tc.remove_task(last);
+        assert!(tc.detach_task(last).is_none());
+        assert_eq!(tc.task_num(), WAKER_PAGE_SIZE);
+    }
+
+    #[test]
+    fn suspended_across_migration() {
+        let from = TaskCollection::new(CPU_ID);
+        let to = TaskCollection::new(CPU_ID);
+        let key = from.add_task(pending()).unwrap();
+        assert!(from.suspend_task(key));
+
+        let task = from.detach_task(key).unwrap();
+        let key = to.attach_task(task).unwrap();
+        assert_eq!(poll_ready(&to), 0, "a migrated task is resumed");
+        assert!(to.resume_task(key));
+        assert_eq!(poll_ready(&to), 1);
+    }
+
+    #[test]
+    fn stale_key_of_reused_slot() {
+        let tc = TaskCollection::new(CPU_ID);
+        let old_key = tc.add_task(pending()).unwrap();
+        let (_, old_task, old_waker, _) = tc.take_task().unwrap();
+        // completed while polled, like `Executor::run` does
+        old_task.replace_state(TaskState::RUNNING, TaskState::RUNNABLE);
+        tc.remove_polled_task(old_key, &old_task);
+
+        let key = tc.add_task(pending()).unwrap();
+        assert_eq!(key, old_key, "the slot is not reused");
+        // the new task is ready at once, nothing of the old one is left in the slot
+        let (_, task, waker, _) = tc.take_task().unwrap();
+        task.replace_state(TaskState::RUNNING, TaskState::RUNNABLE);
+        waker.mark_borrowed(false);
+
+        old_waker.wake_by_ref();
+        assert_eq!(poll_ready(&tc), 0, "woken by the waker of a removed task");
+        assert!(!tc.cancel_task(old_key, old_task.id()));
+        waker.wake_by_ref();
+        assert_eq!(poll_ready(&tc), 1, "cancelled by a stale key");
+        tc.remove_polled_task(old_key, &old_task);
+        assert_eq!(
+            tc.task_num.load(Ordering::Relaxed),
+            1,
+            "removed by a stale key"
+        );
+        waker.wake_by_ref();
+        assert_eq!(poll_ready(&tc), 1, "cleared by a stale key");
+    }
+
+    #[test]
+    fn cancelled_while_polled() {
+        let tc = TaskCollection::new(CPU_ID);
+        let key = tc.add_task(pending()).unwrap();
+        let (_, task, waker, _) = tc.take_task().unwrap();
+        assert!(tc.cancel_task(key, task.id()));
+        assert_eq!(poll_ready(&tc), 0);
+        assert_eq!(
+            tc.task_num.load(Ordering::Relaxed),
+            1,
+            "removed while polled"
+        );
+
+        // returned by the executor, removed on the next scan
+        task.replace_state(TaskState::RUNNING, TaskState::RUNNABLE);
+        waker.mark_borrowed(false);
+        assert_eq!(poll_ready(&tc), 0);
+        assert_eq!(tc.task_num.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(feature = "heapless")]
+    #[test]
+    #[should_panic]
+    fn capacity_over_max_pages() {
+        TaskCollection::with_capacity(CPU_ID, MAX_PAGES * WAKER_PAGE_SIZE + 1);
     }
 }
@@ -0,0 +1,438 @@
+//! Timers. Each cpu has a hierarchical `TimerWheel` in its runtime, expired timers are
+//! woken by `process_timers`, which is called on each `handle_timeout`.
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use crate::clock::{now_cycles, ns_to_cycles, Nanoseconds};
+use lock::Mutex;
+
+const WHEEL_BITS: usize = 6;
+const WHEEL_SLOTS: usize = 1 << WHEEL_BITS;
+const WHEEL_LEVELS: usize = 4;
+// advancing more ticks than this at once re-inserts all timers instead of stepping
+const MAX_STEP_TICKS: u64 = 1 << (WHEEL_BITS * 2);
+
+/// Resolution of the timer wheel of each runtime, in cycles of the clock source.
+pub const DEFAULT_TIMER_RESOLUTION: u64 = 1 << 16;
+
+// states of a timer
+const PENDING: u8 = 0;
+const CANCELLED: u8 = 1;
+const FIRED: u8 = 2;
+
+// shared by a timer entry and its handles
+struct TimerState {
+    state: AtomicU8,
+    // replaced by `TimerHandle::set_waker`, the timer interrupt takes the lock to wake it
+    waker: Mutex<Waker>,
+}
+
+/// Handle of a registered timer, which can be cancelled from any cpu.
+#[derive(Clone)]
+pub struct TimerHandle {
+    timer: Arc<TimerState>,
+    // cancelled entries still in the wheel, see `TimerWheel::len`
+    cancelled: Arc<AtomicUsize>,
+}
+
+impl TimerHandle {
+    pub fn cancel(&self) {
+        // counted first, so that the wheel never drops an entry which is not counted yet
+        self.cancelled.fetch_add(1, Ordering::Relaxed);
+        let cancelled = self.timer.state.compare_exchange(
+            PENDING,
+            CANCELLED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        // fired or cancelled already, the entry is not in the wheel or counted already
+        if cancelled.is_err() {
+            self.cancelled.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Replace the waker of a pending timer, return false if it has fired or has been
+    /// cancelled. The lock is taken by the timer interrupt too, so it is taken with
+    /// interrupts off.
+    pub fn set_waker(&self, waker: &Waker) -> bool {
+        let mut pending = false;
+        super::run_with_intr_saved_off! {
+            let mut current = self.timer.waker.lock();
+            // checked under the lock, a timer fired meanwhile wakes the new waker
+            pending = self.timer.state.load(Ordering::Acquire) == PENDING;
+            if pending && !current.will_wake(waker) {
+                *current = waker.clone();
+            }
+        }
+        pending
+    }
+}
+
+struct TimerEntry {
+    // in ticks
+    expiry: u64,
+    timer: Arc<TimerState>,
+}
+
+impl TimerEntry {
+    fn cancelled(&self) -> bool {
+        self.timer.state.load(Ordering::Acquire) == CANCELLED
+    }
+
+    // return false if it has been cancelled
+    fn fire(&self) -> bool {
+        let fired =
+            self.timer
+                .state
+                .compare_exchange(PENDING, FIRED, Ordering::AcqRel, Ordering::Acquire);
+        if fired.is_ok() {
+            self.timer.waker.lock().wake_by_ref();
+        }
+        fired.is_ok()
+    }
+}
+
+/// A hierarchical timer wheel, insertion and expiry are amortized O(1).
+///
+/// Level `l` has 64 slots of `64^l` ticks each. A timer is put into the lowest level which
+/// covers its expiry, and moved to lower levels as the wheel advances.
+pub struct TimerWheel {
+    // cycles per tick
+    resolution: u64,
+    // current tick, all timers expiring at or before it have been woken
+    now: u64,
+    levels: Vec<Vec<Vec<TimerEntry>>>,
+    // timers beyond the range of the wheel
+    overflow: Vec<TimerEntry>,
+    // number of entries in the wheel, including the cancelled ones
+    len: usize,
+    // number of cancelled entries in the wheel, they are dropped once the wheel reaches
+    // them, incremented by `TimerHandle::cancel` on any cpu
+    cancelled: Arc<AtomicUsize>,
+}
+
+impl TimerWheel {
+    pub fn new(resolution: u64, now_cycles: u64) -> Self {
+        let resolution = resolution.max(1);
+        let levels = (0..WHEEL_LEVELS)
+            .map(|_| (0..WHEEL_SLOTS).map(|_| Vec::new()).collect())
+            .collect();
+        Self {
+            resolution,
+            now: now_cycles / resolution,
+            levels,
+            overflow: Vec::new(),
+            len: 0,
+            cancelled: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Wake `waker` once the clock reaches `expiry` (in cycles).
+    pub fn insert(&mut self, waker: Waker, expiry: u64) -> TimerHandle {
+        let timer = Arc::new(TimerState {
+            state: AtomicU8::new(PENDING),
+            waker: Mutex::new(waker),
+        });
+        // round up without overflowing, the timer never fires before `expiry`
+        let expiry = expiry / self.resolution + u64::from(expiry % self.resolution != 0);
+        // the slot of current tick has been processed, fire at the next tick at least
+        let expiry = expiry.max(self.now + 1);
+        self.place(TimerEntry {
+            expiry,
+            timer: timer.clone(),
+        });
+        self.len += 1;
+        TimerHandle {
+            timer,
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    pub fn remove(&mut self, handle: &TimerHandle) {
+        // the entry is dropped when the wheel reaches it, it is not counted meanwhile
+        handle.cancel();
+    }
+
+    /// Number of pending timers, the cancelled ones are not counted.
+    pub fn len(&self) -> usize {
+        self.len
+            .saturating_sub(self.cancelled.load(Ordering::Relaxed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wake all the timers whose expiry is not after `now` (in cycles).
+    pub fn advance(&mut self, now: u64) {
+        let target = now / self.resolution;
+        if target <= self.now {
+            return;
+        }
+        if self.len == 0 {
+            self.now = target;
+            return;
+        }
+        if target - self.now > MAX_STEP_TICKS {
+            self.now = target;
+            for entry in self.drain() {
+                self.len -= 1;
+                self.fire_or_place(entry);
+            }
+            return;
+        }
+        while self.now < target {
+            self.now += 1;
+            self.cascade();
+            let slot = (self.now as usize) & (WHEEL_SLOTS - 1);
+            let entries = core::mem::take(&mut self.levels[0][slot]);
+            for entry in entries {
+                self.len -= 1;
+                self.fire_or_place(entry);
+            }
+        }
+    }
+
+    fn place(&mut self, entry: TimerEntry) {
+        let delta = entry.expiry.saturating_sub(self.now);
+        for level in 0..WHEEL_LEVELS {
+            if delta >> (WHEEL_BITS * (level + 1)) == 0 {
+                let slot = ((entry.expiry >> (WHEEL_BITS * level)) as usize) & (WHEEL_SLOTS - 1);
+                self.levels[level][slot].push(entry);
+                return;
+            }
+        }
+        self.overflow.push(entry);
+    }
+
+    fn fire_or_place(&mut self, entry: TimerEntry) {
+        if entry.cancelled() {
+            // dropped, it is no longer counted as cancelled
+            self.cancelled.fetch_sub(1, Ordering::Relaxed);
+        } else if entry.expiry > self.now {
+            self.place(entry);
+            self.len += 1;
+        } else if !entry.fire() {
+            // cancelled after the check above
+            self.cancelled.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // move the timers of higher levels down when the lower level wraps around
+    fn cascade(&mut self) {
+        for level in 1..WHEEL_LEVELS {
+            if self.now & ((1 << (WHEEL_BITS * level)) - 1) != 0 {
+                return;
+            }
+            let slot = ((self.now >> (WHEEL_BITS * level)) as usize) & (WHEEL_SLOTS - 1);
+            let entries = core::mem::take(&mut self.levels[level][slot]);
+            for entry in entries {
+                self.len -= 1;
+                self.fire_or_place(entry);
+            }
+        }
+        if self.now & ((1 << (WHEEL_BITS * WHEEL_LEVELS)) - 1) == 0 {
+            for entry in core::mem::take(&mut self.overflow) {
+                self.len -= 1;
+                self.fire_or_place(entry);
+            }
+        }
+    }
+
+    fn drain(&mut self) -> Vec<TimerEntry> {
+        let mut entries = core::mem::take(&mut self.overflow);
+        for level in self.levels.iter_mut() {
+            for slot in level.iter_mut() {
+                entries.append(slot);
+            }
+        }
+        entries
+    }
+}
+
+/// Wake `waker` once the clock reaches `deadline`, on current cpu. The wheel is shared
+/// with `handle_timeout`, so it is updated with interrupts off.
+pub fn register_timer(deadline: u64, waker: Waker) -> TimerHandle {
+    let mut handle = None;
+    super::run_with_intr_saved_off! {
+        handle = Some(
+            crate::runtime::get_current_runtime()
+                .timer_wheel
+                .insert(waker, deadline),
+        )
+    }
+    handle.unwrap()
+}
+
+pub fn cancel_timer(handle: &TimerHandle) {
+    handle.cancel();
+}
+
+/// Wake all the expired timers of current cpu.
+pub fn process_timers() {
+    let now = now_cycles();
+    super::run_with_intr_saved_off! {
+        crate::runtime::get_current_runtime()
+            .timer_wheel
+            .advance(now)
+    }
+}
+
+pub(crate) fn duration_to_cycles(duration: Duration) -> u64 {
+    ns_to_cycles(Nanoseconds(duration.as_nanos() as u64))
+}
+
+/// Fire at a fixed rate. The n-th tick is at `start + n * period`, so the interval does
+/// not drift. If some ticks are missed, the next `tick` returns at once and the number of
+/// missed ticks is recorded.
+pub struct Interval {
+    start: u64,
+    period: u64,
+    // index of the next tick
+    next: u64,
+    // number of ticks missed before the last tick
+    missed: u64,
+    // the timer registered for the deadline of the next tick
+    timer: Option<(u64, TimerHandle)>,
+}
+
+impl Interval {
+    pub fn new(period: Duration) -> Self {
+        let period = duration_to_cycles(period).max(1);
+        Self {
+            start: now_cycles(),
+            period,
+            next: 1,
+            missed: 0,
+            timer: None,
+        }
+    }
+
+    /// Wait for the next tick, return its deadline in cycles.
+    pub fn tick(&mut self) -> Tick<'_> {
+        Tick { interval: self }
+    }
+
+    pub fn missed_ticks(&self) -> u64 {
+        self.missed
+    }
+
+    fn deadline(&self) -> u64 {
+        self.start + self.next * self.period
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) {
+        if let Some((_, handle)) = self.timer.take() {
+            cancel_timer(&handle);
+        }
+    }
+}
+
+pub struct Tick<'a> {
+    interval: &'a mut Interval,
+}
+
+impl Future for Tick<'_> {
+    type Output = u64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u64> {
+        let interval = &mut *self.get_mut().interval;
+        let deadline = interval.deadline();
+        let now = now_cycles();
+        if now >= deadline {
+            if let Some((_, handle)) = interval.timer.take() {
+                cancel_timer(&handle);
+            }
+            interval.missed = (now - deadline) / interval.period;
+            interval.next += interval.missed + 1;
+            return Poll::Ready(deadline);
+        }
+        // a spurious poll keeps the timer registered for the same deadline, only its waker
+        // is updated
+        let reused = match &interval.timer {
+            Some((registered, handle)) => *registered == deadline && handle.set_waker(cx.waker()),
+            None => false,
+        };
+        if !reused {
+            if let Some((_, handle)) = interval.timer.take() {
+                cancel_timer(&handle);
+            }
+            let handle = register_timer(deadline, cx.waker().clone());
+            interval.timer = Some((deadline, handle));
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::Wake;
+
+    struct CountWakes(AtomicUsize);
+
+    impl Wake for CountWakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn counting_waker() -> (Arc<CountWakes>, Waker) {
+        let wakes = Arc::new(CountWakes(AtomicUsize::new(0)));
+        (wakes.clone(), Waker::from(wakes))
+    }
+
+    #[test]
+    fn cancelled_timers_are_not_counted() {
+        let mut wheel = TimerWheel::new(1, 0);
+        let (wakes, waker) = counting_waker();
+        let cancelled = wheel.insert(waker.clone(), 10);
+        wheel.insert(waker, 20);
+        assert_eq!(wheel.len(), 2);
+        cancelled.cancel();
+        cancelled.cancel();
+        assert_eq!(wheel.len(), 1);
+        wheel.advance(15);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        wheel.advance(20);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert!(wheel.is_empty());
+        assert_eq!(wheel.cancelled.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn set_waker_of_pending_timer() {
+        let mut wheel = TimerWheel::new(1, 0);
+        let (old_wakes, old_waker) = counting_waker();
+        let (new_wakes, new_waker) = counting_waker();
+        let handle = wheel.insert(old_waker, 10);
+        assert!(handle.set_waker(&new_waker));
+        wheel.advance(10);
+        assert_eq!(old_wakes.0.load(Ordering::SeqCst), 0);
+        assert_eq!(new_wakes.0.load(Ordering::SeqCst), 1);
+        // fired, cancelling it is not counted
+        assert!(!handle.set_waker(&new_waker));
+        handle.cancel();
+        assert_eq!(wheel.cancelled.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn far_deadline() {
+        let mut wheel = TimerWheel::new(DEFAULT_TIMER_RESOLUTION, 0);
+        let (wakes, waker) = counting_waker();
+        wheel.insert(waker, u64::MAX);
+        wheel.advance(u64::MAX - 1);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        assert_eq!(wheel.len(), 1);
+    }
+}
@@ -1,117 +1,279 @@
+use alloc::alloc::AllocError;
 use alloc::sync::Arc;
+// build with `RUSTFLAGS="--cfg loom"` to model check the atomic operations with loom
+#[cfg(not(loom))]
 use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 // use core::task::{RawWaker, RawWakerVTable};
 use woke::Woke;
 
-#[derive(Debug)]
-pub struct AtomicU64SC(AtomicU64);
-pub const WAKER_PAGE_SIZE: usize = 64;
+// Define a function which is `const` except with loom, whose atomics cannot be created
+// in const context, so that its body is written only once.
+macro_rules! maybe_const_fn {
+    ($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+        $(#[$attr])*
+        #[cfg(not(loom))]
+        $vis const fn $($rest)*
 
-impl AtomicU64SC {
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn new(val: u64) -> Self {
-        AtomicU64SC(AtomicU64::new(val))
-    }
+        $(#[$attr])*
+        #[cfg(loom)]
+        $vis fn $($rest)*
+    };
+}
 
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn fetch_or(&self, val: u64) {
-        self.0.fetch_or(val, Ordering::SeqCst);
-    }
+macro_rules! atomic_sc {
+    ($name:ident, $atomic:ty, $int:ty) => {
+        /// An atomic integer whose operations are all `SeqCst`.
+        #[derive(Debug)]
+        pub struct $name($atomic);
 
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn fetch_and(&self, val: u64) {
-        self.0.fetch_and(val, Ordering::SeqCst);
-    }
+        impl $name {
+            maybe_const_fn! {
+                #[inline(always)]
+                #[allow(unused)]
+                pub fn new(val: $int) -> Self {
+                    $name(<$atomic>::new(val))
+                }
+            }
 
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn fetch_add(&self, val: u64) -> u64 {
-        self.0.fetch_add(val, Ordering::SeqCst)
-    }
+            #[inline(always)]
+            #[allow(unused)]
+            pub fn fetch_or(&self, val: $int) -> $int {
+                self.0.fetch_or(val, Ordering::SeqCst)
+            }
 
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn fetch_sub(&self, val: u64) -> u64 {
-        self.0.fetch_sub(val, Ordering::SeqCst)
+            #[inline(always)]
+            #[allow(unused)]
+            pub fn fetch_and(&self, val: $int) -> $int {
+                self.0.fetch_and(val, Ordering::SeqCst)
+            }
+
+            #[inline(always)]
+            #[allow(unused)]
+            pub fn fetch_add(&self, val: $int) -> $int {
+                self.0.fetch_add(val, Ordering::SeqCst)
+            }
+
+            #[inline(always)]
+            #[allow(unused)]
+            pub fn fetch_sub(&self, val: $int) -> $int {
+                self.0.fetch_sub(val, Ordering::SeqCst)
+            }
+
+            #[inline(always)]
+            #[allow(unused)]
+            pub fn load(&self) -> $int {
+                self.0.load(Ordering::SeqCst)
+            }
+
+            #[inline(always)]
+            #[allow(unused)]
+            pub fn swap(&self, val: $int) -> $int {
+                self.0.swap(val, Ordering::SeqCst)
+            }
+
+            #[inline(always)]
+            #[allow(unused)]
+            #[cfg(not(loom))]
+            pub fn as_mut_ptr(&mut self) -> *mut $int {
+                self.0.as_mut_ptr()
+            }
+        }
+    };
+}
+
+atomic_sc!(AtomicU64SC, AtomicU64, u64);
+
+// Pages hold 128 futures on platforms with 128-bit atomics, which halves the number of pages.
+cfg_if::cfg_if! {
+    if #[cfg(all(target_has_atomic = "128", not(loom)))] {
+        atomic_sc!(AtomicU128SC, core::sync::atomic::AtomicU128, u128);
+
+        /// A bit vector holding one bit per future of a page.
+        pub type Bitmask = u128;
+        type AtomicBitmask = AtomicU128SC;
+        pub const WAKER_PAGE_SIZE: usize = 128;
+    } else {
+        /// A bit vector holding one bit per future of a page.
+        pub type Bitmask = u64;
+        type AtomicBitmask = AtomicU64SC;
+        pub const WAKER_PAGE_SIZE: usize = 64;
     }
+}
 
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn load(&self) -> u64 {
-        self.0.load(Ordering::SeqCst)
+/// Pad a value to its own cache line, to avoid false sharing with its neighbours.
+#[derive(Debug)]
+#[repr(align(64))]
+pub struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    pub const fn new(val: T) -> Self {
+        CachePadded(val)
     }
+}
 
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn swap(&self, val: u64) -> u64 {
-        self.0.swap(val, Ordering::SeqCst)
+impl<T> core::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
     }
+}
 
-    #[inline(always)]
-    #[allow(unused)]
-    pub fn as_mut_ptr(&mut self) -> *mut u64 {
-        self.0.as_mut_ptr()
+/// The bitmasks of a page, see `WakerPage::debug_dump`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakerPageDump {
+    pub notified: Bitmask,
+    pub dropped: Bitmask,
+    pub borrowed: Bitmask,
+    pub suspended: Bitmask,
+}
+
+impl WakerPageDump {
+    pub fn is_empty(&self) -> bool {
+        self.notified | self.dropped | self.borrowed | self.suspended == 0
     }
 }
 
 /// A page is used by the scheduler to hold the current status of 64 different futures in the
 /// scheduler. So we use 64bit integers where the ith bit represents the ith future. Pages are
 /// arranged by the scheduler in a `pages` vector of pages which grows as needed allocating space
-/// for 64 more futures at a time.
+/// for 64 more futures at a time. With 128-bit atomics, a page holds 128 futures instead.
 #[derive(Debug)]
 #[repr(align(64))]
 pub struct WakerPage {
     /// A 64 element bit vector representing the futures for this page which have been notified
     /// by a wake and are ready to be polled again. The ith bit represents the ith future in the
     /// corresponding memory slab.
-    notified: AtomicU64SC,
+    ///
+    /// Each bitmask has its own cache line: `notified` is written by waking cpus, while
+    /// `borrowed` and `dropped` are mostly accessed by the cpu running the futures.
+    notified: CachePadded<AtomicBitmask>,
     // completed: AtomicU64SC,
-    dropped: AtomicU64SC,
-    borrowed: AtomicU64SC,
+    dropped: CachePadded<AtomicBitmask>,
+    borrowed: CachePadded<AtomicBitmask>,
+    /// Futures excluded from scheduling until resumed, their notifications are kept.
+    suspended: CachePadded<AtomicBitmask>,
+    // slots between `initialize` and `clear`, only tracked to check `make_waker`
+    #[cfg(debug_assertions)]
+    initialized: AtomicBitmask,
+    // the cpu whose executors poll the futures of this page
+    cpu_id: u8,
+    // number of notified futures of the collection owning this page, see
+    // `FutureCollection::ready_count`. `None` for a page out of any collection.
+    ready_count: Option<Arc<core::sync::atomic::AtomicUsize>>,
 }
 
 impl WakerPage {
-    pub fn new_inner() -> Self {
-        WakerPage {
-            notified: AtomicU64SC::new(0),
-            // completed: AtomicU64SC::new(0),
-            dropped: AtomicU64SC::new(0),
-            borrowed: AtomicU64SC::new(0),
+    // the notified bits of the page changed from `prev` to `prev | set`
+    fn add_ready(&self, prev: Bitmask, set: Bitmask) {
+        let added = (set & !prev).count_ones() as usize;
+        if added != 0 {
+            if let Some(count) = &self.ready_count {
+                count.fetch_add(added, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // the notified bits of the page changed from `prev` to `prev & !cleared`
+    fn sub_ready(&self, prev: Bitmask, cleared: Bitmask) {
+        let removed = (prev & cleared).count_ones() as usize;
+        if removed != 0 {
+            if let Some(count) = &self.ready_count {
+                count.fetch_sub(removed, Ordering::Relaxed);
+            }
         }
     }
 
-    pub fn new() -> Arc<Self> {
-        Arc::new(WakerPage::new_inner())
+    maybe_const_fn! {
+        /// `const`, so that pages can be put in static memory.
+        pub fn new_inner(
+            cpu_id: u8,
+            ready_count: Option<Arc<core::sync::atomic::AtomicUsize>>,
+        ) -> Self {
+            WakerPage {
+                notified: CachePadded::new(AtomicBitmask::new(0)),
+                // completed: AtomicU64SC::new(0),
+                dropped: CachePadded::new(AtomicBitmask::new(0)),
+                borrowed: CachePadded::new(AtomicBitmask::new(0)),
+                suspended: CachePadded::new(AtomicBitmask::new(0)),
+                #[cfg(debug_assertions)]
+                initialized: AtomicBitmask::new(0),
+                cpu_id,
+                ready_count,
+            }
+        }
+    }
+
+    /// Create a page whose notified futures are counted in `ready_count`, if any.
+    pub fn new(cpu_id: u8, ready_count: Option<Arc<core::sync::atomic::AtomicUsize>>) -> Arc<Self> {
+        Arc::new(WakerPage::new_inner(cpu_id, ready_count))
+    }
+
+    pub fn try_new(
+        cpu_id: u8,
+        ready_count: Option<Arc<core::sync::atomic::AtomicUsize>>,
+    ) -> Result<Arc<Self>, AllocError> {
+        Arc::try_new(WakerPage::new_inner(cpu_id, ready_count))
+    }
+
+    pub fn cpu_id(&self) -> u8 {
+        self.cpu_id
     }
 
+    /// Reset the slot for a new future, which is notified. The other bits are cleared first,
+    /// so that the new future is never seen notified with the bits left by the old one.
     pub fn initialize(&self, idx: usize) {
-        debug_assert!(idx < 64);
-        self.notified.fetch_or(1 << idx);
+        debug_assert!(idx < WAKER_PAGE_SIZE);
         // self.completed.fetch_and(!(1 << idx));
         self.dropped.fetch_and(!(1 << idx));
         self.borrowed.fetch_and(!(1 << idx));
+        self.suspended.fetch_and(!(1 << idx));
+        #[cfg(debug_assertions)]
+        self.initialized.fetch_or(1 << idx);
+        let prev = self.notified.fetch_or(1 << idx);
+        self.add_ready(prev, 1 << idx);
+    }
+
+    /// Whether the slot holds a future, i.e. it is initialized and not cleared. Only
+    /// tracked in debug builds, always true otherwise.
+    pub fn is_initialized(&self, idx: usize) -> bool {
+        debug_assert!(idx < WAKER_PAGE_SIZE);
+        #[cfg(debug_assertions)]
+        return self.initialized.load() & (1 << idx) != 0;
+        #[cfg(not(debug_assertions))]
+        true
     }
 
     pub fn mark_dropped(&self, idx: usize) {
-        debug_assert!(idx < 64);
+        debug_assert!(idx < WAKER_PAGE_SIZE);
         self.dropped.fetch_or(1 << idx);
     }
 
     // pub fn mark_complete(&self, idx: usize) {
-    //     debug_assert!(idx < 64);
+    //     debug_assert!(idx < WAKER_PAGE_SIZE);
     //     self.completed.fetch_or(1 << idx);
     // }
 
     pub fn notify(&self, offset: usize) {
-        debug_assert!(offset < 64);
-        self.notified.fetch_or(1 << offset);
+        debug_assert!(offset < WAKER_PAGE_SIZE);
+        let prev = self.notified.fetch_or(1 << offset);
+        self.add_ready(prev, 1 << offset);
+    }
+
+    /// Wake all the futures whose bit is set in `mask` with a single atomic operation.
+    /// Futures that have already been dropped are filtered out.
+    pub fn notify_batch(&self, mask: Bitmask) {
+        let mask = mask & !self.dropped.load();
+        if mask != 0 {
+            let prev = self.notified.fetch_or(mask);
+            self.add_ready(prev, mask);
+        }
     }
 
     pub fn mark_borrowed(&self, offset: usize, borrowed: bool) {
-        debug_assert!(offset < 64);
+        debug_assert!(offset < WAKER_PAGE_SIZE);
         if borrowed {
             self.borrowed.fetch_or(1 << offset);
         } else {
@@ -119,51 +281,146 @@ impl WakerPage {
         }
     }
 
+    pub fn suspend(&self, idx: usize) {
+        debug_assert!(idx < WAKER_PAGE_SIZE);
+        self.suspended.fetch_or(1 << idx);
+    }
+
+    /// Resume a suspended future, return whether it has been notified meanwhile.
+    pub fn resume(&self, idx: usize) -> bool {
+        debug_assert!(idx < WAKER_PAGE_SIZE);
+        self.suspended.fetch_and(!(1 << idx));
+        self.is_notified(idx)
+    }
+
+    pub fn is_suspended(&self, idx: usize) -> bool {
+        debug_assert!(idx < WAKER_PAGE_SIZE);
+        self.suspended.load() & (1 << idx) != 0
+    }
+
     // pub fn mark_completed(&self, offset: usize) {
-    //     debug_assert!(offset < 64);
+    //     debug_assert!(offset < WAKER_PAGE_SIZE);
     //     self.completed.fetch_or(1 << offset);
     // }
 
     /// Return a bit vector representing the futures in this page which are ready to be
     /// polled again.
-    pub fn take_notified(&self) -> u64 {
+    pub fn take_notified(&self) -> Bitmask {
         // Unset all ready bits, since spurious notifications for completed futures would lead
         // us to poll them after completion.
-        let mut notified = self.notified.swap(0);
+        // The bits of borrowed futures are kept: they are being polled by an executor, and
+        // must be polled again once it is done with them. So are the bits of suspended
+        // futures, which are polled once resumed.
+        let kept = self.borrowed.load() | self.suspended.load();
+        let mut notified = self.notified.fetch_and(kept);
+        self.sub_ready(notified, !kept);
         // notified &= !self.completed.load();
         notified &= !self.dropped.load();
-        notified &= !self.borrowed.load();
+        notified &= !kept;
         notified
     }
 
-    pub fn take_dropped(&self) -> u64 {
-        self.dropped.swap(0)
+    pub fn notified(&self) -> Bitmask {
+        self.notified.load()
+    }
+
+    /// Read all the bitmasks without any lock, e.g. from a panic handler. They are read one
+    /// by one, so they may be inconsistent if the page is changed meanwhile.
+    pub fn debug_dump(&self) -> WakerPageDump {
+        WakerPageDump {
+            notified: self.notified.load(),
+            dropped: self.dropped.load(),
+            borrowed: self.borrowed.load(),
+            suspended: self.suspended.load(),
+        }
+    }
+
+    pub fn borrowed(&self) -> Bitmask {
+        self.borrowed.load()
+    }
+
+    pub fn suspended(&self) -> Bitmask {
+        self.suspended.load()
+    }
+
+    /// Whether any future of this page is ready to be polled, without consuming the
+    /// notifications like `take_notified`.
+    pub fn is_any_notified(&self) -> bool {
+        let masked = self.dropped.load() | self.borrowed.load() | self.suspended.load();
+        self.notified.load() & !masked != 0
+    }
+
+    /// Whether any future can be taken by `take_dropped`.
+    pub fn has_dropped(&self) -> bool {
+        self.dropped.load() & !self.borrowed.load() != 0
     }
 
+    pub fn is_notified(&self, idx: usize) -> bool {
+        debug_assert!(idx < WAKER_PAGE_SIZE);
+        self.notified.load() & (1 << idx) != 0
+    }
+
+    pub fn is_borrowed(&self, idx: usize) -> bool {
+        debug_assert!(idx < WAKER_PAGE_SIZE);
+        self.borrowed.load() & (1 << idx) != 0
+    }
+
+    /// Take the dropped futures which are not borrowed. A borrowed one is still being polled,
+    /// its bit is kept until the executor returns it, so that its slot is never reused while
+    /// the poll is in flight.
+    pub fn take_dropped(&self) -> Bitmask {
+        let borrowed = self.borrowed.load();
+        self.dropped.fetch_and(borrowed) & !borrowed
+    }
+
+    /// Reset all the bits of the slot, including `borrowed`, so that a future inserted to the
+    /// slot later is not masked by the state of the removed one.
     pub fn clear(&self, idx: usize) {
-        debug_assert!(idx < 64);
+        debug_assert!(idx < WAKER_PAGE_SIZE);
         let mask = !(1 << idx);
-        self.notified.fetch_and(mask);
+        let prev = self.notified.fetch_and(mask);
+        self.sub_ready(prev, 1 << idx);
+        #[cfg(debug_assertions)]
+        self.initialized.fetch_and(mask);
         // self.completed.fetch_and(mask);
         self.dropped.fetch_and(mask);
-        self.borrowed.fetch_and(mask)
+        self.borrowed.fetch_and(mask);
+        self.suspended.fetch_and(mask);
     }
 
-    pub fn make_waker(self: &Arc<Self>, idx: usize, dropped: &Arc<AtomicBool>) -> WakerRef {
+    /// Make a waker of the future in slot `subpage_idx`, which must be initialized: waking
+    /// an empty slot would get a missing task scheduled.
+    pub fn make_waker(self: &Arc<Self>, subpage_idx: usize, dropped: &Arc<AtomicBool>) -> WakerRef {
+        debug_assert!(subpage_idx < WAKER_PAGE_SIZE);
+        debug_assert!(
+            self.is_initialized(subpage_idx),
+            "make a waker of an empty slot {}",
+            subpage_idx
+        );
         WakerRef {
             page: self.clone(),
-            idx,
+            idx: subpage_idx,
             dropped: dropped.clone(),
+            cpu_id: self.cpu_id,
         }
     }
 }
 
+impl Drop for WakerPage {
+    fn drop(&mut self) {
+        // notifications left on a released page are no longer ready
+        self.sub_ready(self.notified.load(), !0);
+    }
+}
+
 pub type DroperRef = WakerRef;
 
 pub struct WakerRef {
     page: Arc<WakerPage>,
     idx: usize,
     dropped: Arc<AtomicBool>,
+    // the cpu polling the future, copied from the page when the waker is made
+    cpu_id: u8,
 }
 
 impl WakerRef {
@@ -175,12 +432,41 @@ impl WakerRef {
         self.page.mark_borrowed(self.idx, borrowed);
     }
 
+    #[cfg_attr(debug_assertions, inline(never))]
     pub fn wake_by_ref(&self) {
         if !self.dropped.load(Ordering::SeqCst) {
             self.page.notify(self.idx);
+            self.kick();
         }
     }
 
+    /// Exclude the future from scheduling until `resume` is called. Wakeups meanwhile are
+    /// not lost, the future is polled once resumed.
+    pub fn suspend(&self) {
+        self.page.suspend(self.idx);
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.page.is_suspended(self.idx)
+    }
+
+    pub fn resume(&self) {
+        if self.page.resume(self.idx) {
+            self.kick();
+        }
+    }
+
+    // the cpu running the future may be waiting for interrupts
+    fn kick(&self) {
+        if self.cpu_id != crate::arch::cpu_id() {
+            crate::ipi::send_ipi(self.cpu_id);
+        }
+    }
+
+    pub fn cpu_id(&self) -> u8 {
+        self.cpu_id
+    }
+
     pub fn drop_by_ref(&self) {
         if !self.dropped.swap(true, Ordering::SeqCst) {
             self.page.mark_dropped(self.idx);
@@ -194,12 +480,80 @@ impl Woke for WakerRef {
     }
 }
 
+/// Two `WakerRef`s are equal if they wake the same future, so that wait queues can
+/// deduplicate them.
+impl PartialEq for WakerRef {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.page, &other.page) && self.idx == other.idx
+    }
+}
+
+impl Eq for WakerRef {}
+
 impl Clone for WakerRef {
     fn clone(&self) -> Self {
         WakerRef {
             page: self.page.clone(),
             idx: self.idx,
             dropped: self.dropped.clone(),
+            cpu_id: self.cpu_id,
         }
     }
 }
+
+// RUSTFLAGS="--cfg loom" cargo test --release --features std loom_tests
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::thread;
+
+    // a notification racing with `take_notified` is either taken or kept for the next scan
+    #[test]
+    fn notify_is_never_lost() {
+        loom::model(|| {
+            let page = WakerPage::new(0, None);
+            let page2 = page.clone();
+            let waker = thread::spawn(move || page2.notify(0));
+            let taken = page.take_notified();
+            waker.join().unwrap();
+            assert_eq!(taken | page.take_notified(), 1);
+        });
+    }
+
+    // a future woken while it is polled is not taken again until it is returned, and is
+    // polled again afterwards
+    #[test]
+    fn borrowed_future_is_not_taken() {
+        loom::model(|| {
+            let page = WakerPage::new(0, None);
+            page.initialize(0);
+            assert_eq!(page.take_notified(), 1);
+            page.mark_borrowed(0, true);
+            let page2 = page.clone();
+            let waker = thread::spawn(move || page2.notify(0));
+            assert_eq!(page.take_notified(), 0);
+            waker.join().unwrap();
+            assert_eq!(page.take_notified(), 0);
+            page.mark_borrowed(0, false);
+            assert_eq!(page.take_notified(), 1);
+        });
+    }
+
+    // a future woken while it is dropped is never returned notified
+    #[test]
+    fn dropped_future_is_not_taken() {
+        loom::model(|| {
+            let page = WakerPage::new(0, None);
+            page.initialize(0);
+            assert_eq!(page.take_notified(), 1);
+            let finish = Arc::new(AtomicBool::new(false));
+            let waker = page.make_waker(0, &finish);
+            let droper = waker.clone();
+            let waker = thread::spawn(move || waker.wake_by_ref());
+            droper.drop_by_ref();
+            waker.join().unwrap();
+            assert_eq!(page.take_notified(), 0);
+            assert_eq!(page.take_dropped(), 1);
+        });
+    }
+}